@@ -0,0 +1,121 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use kiri::evdev_keys::*;
+use kiri::{
+    parse_trace, AddLayer, KeyConfig, KeyConfigRun, KeyInput, KeyPattern, PairRemapEntry,
+    PatternInput, PatternOutput, RemapLayer, SinglePatternEntry, SingleRemapEntry,
+};
+
+/// Generated traces run through the layers of [`layers`].
+const TRACES: &[(&str, &str)] = &[("typing", include_str!("typing.trace"))];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+enum Sands {
+    Normal,
+    Space,
+    Shift,
+}
+
+/// Space as shift when held with another key, and as space when tapped alone.
+fn sands() -> RemapLayer<Sands> {
+    use Sands::*;
+    let entry = |condition, input, output: &[KeyInput], transition| SingleRemapEntry {
+        condition,
+        input,
+        output: output.to_vec(),
+        transition,
+    };
+    RemapLayer {
+        single_remap_entries: vec![
+            entry(
+                Normal,
+                KeyInput::press(KEY_SPACE),
+                &[KeyInput::press(KEY_LEFTSHIFT)],
+                Space,
+            ),
+            entry(Space, KeyInput::press(KEY_SPACE), &[], Space),
+            entry(Shift, KeyInput::press(KEY_SPACE), &[], Shift),
+            entry(
+                Space,
+                KeyInput::release(KEY_SPACE),
+                &[
+                    KeyInput::release(KEY_LEFTSHIFT),
+                    KeyInput::press(KEY_SPACE),
+                    KeyInput::release(KEY_SPACE),
+                ],
+                Normal,
+            ),
+            entry(
+                Shift,
+                KeyInput::release(KEY_SPACE),
+                &[KeyInput::release(KEY_LEFTSHIFT)],
+                Normal,
+            ),
+        ],
+        single_pattern_entries: vec![SinglePatternEntry {
+            condition: Space,
+            input: PatternInput::press(KeyPattern::Any),
+            output: vec![PatternOutput::PressMatched],
+            transition: Shift,
+        }],
+        pair_remap_entries: Vec::new(),
+        tap_dance_entries: Vec::new(),
+        pair_pattern_entries: Vec::new(),
+        layer_name: "SandS",
+        initial_state: Normal,
+    }
+}
+
+/// Pairs of keys typed together sending kana, like a simultaneous input layout.
+fn pairs() -> RemapLayer<()> {
+    let pairs = [
+        ([KEY_D, KEY_J], &[KEY_A][..]),
+        ([KEY_K, KEY_F], &[KEY_M, KEY_O]),
+        ([KEY_K, KEY_D], &[KEY_R, KEY_E]),
+        ([KEY_L, KEY_A], &[KEY_W, KEY_O]),
+    ];
+    RemapLayer {
+        pair_remap_entries: pairs
+            .into_iter()
+            .flat_map(|(input, output)| {
+                PairRemapEntry {
+                    condition: (),
+                    input: input.map(KeyInput::press),
+                    output: output
+                        .iter()
+                        .flat_map(|k| [KeyInput::press(*k), KeyInput::release(*k)])
+                        .collect(),
+                    transition: (),
+                    threshold: 50,
+                    hold: false,
+                }
+                .order_insensitive()
+            })
+            .collect(),
+        layer_name: "pairs",
+        ..Default::default()
+    }
+}
+
+fn layers() -> impl KeyConfigRun {
+    KeyConfig::default().add_layer(sands()).add_layer(pairs())
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut simulator = layers().simulator();
+    let mut group = c.benchmark_group("pipeline");
+    for (name, trace) in TRACES {
+        let trace = parse_trace(trace).unwrap();
+        group.throughput(Throughput::Elements(trace.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let keys = simulator.replay(&trace);
+                simulator.reset();
+                keys
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
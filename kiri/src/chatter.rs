@@ -0,0 +1,175 @@
+use crate::{completes_chord, get_keyboard_devices, read_devices, Event};
+use evdev::{InputEvent, InputEventKind, Key};
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+use std::fmt::Write as _;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Release→press intervals shorter than this are too fast for a human
+/// pressing the same key again. milli sec.
+const CHATTER_THRESHOLD: u128 = 30;
+
+/// Upper bounds of the histogram buckets. milli sec.
+const BUCKETS: [u128; 7] = [5, 10, 20, 30, 50, 100, u128::MAX];
+
+#[derive(Debug, Default)]
+struct KeyStats {
+    presses: usize,
+    last_release: Option<SystemTime>,
+    /// Number of release→press intervals in each bucket.
+    histogram: [usize; BUCKETS.len()],
+    /// The longest interval shorter than `CHATTER_THRESHOLD`.
+    max_chatter: Option<u128>,
+}
+
+impl KeyStats {
+    fn chatters(&self) -> usize {
+        BUCKETS
+            .iter()
+            .zip(self.histogram)
+            .filter(|(b, _)| **b <= CHATTER_THRESHOLD)
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// A debounce window a bit longer than the observed chattering,
+    /// rounded up to 5 ms.
+    fn suggested_window(&self) -> Option<u32> {
+        self.max_chatter
+            .map(|ms| ((ms as u32 + 5) / 5 + 1) * 5)
+            .map(|w| w.min(CHATTER_THRESHOLD as u32))
+    }
+
+    fn write(&self, key: Key, report: &mut String) {
+        let _ = writeln!(
+            report,
+            "{key:?}: {} presses, {} too fast",
+            self.presses,
+            self.chatters()
+        );
+        let max = self.histogram.iter().max().copied().unwrap_or(0).max(1);
+        let mut lower = 0;
+        for (upper, n) in BUCKETS.iter().zip(self.histogram) {
+            let range = if *upper == u128::MAX {
+                format!("{lower:>4}-     ms")
+            } else {
+                format!("{lower:>4}-{upper:>4} ms")
+            };
+            let _ = writeln!(report, "    {range} | {:<40} {n}", "#".repeat(n * 40 / max));
+            lower = *upper;
+        }
+    }
+}
+
+/// Watch the keyboards without grabbing them and report keys whose release→press
+/// intervals are too short to be typed by a human, with suggested debounce windows.
+/// Runs for `duration` or until the default emergency stop chord is pressed.
+/// The remapper must not be running, since it grabs the keyboards.
+pub fn diagnose_chattering(duration: Duration) {
+    let keyboards = get_keyboard_devices().map(|(_, d)| d).collect::<Vec<_>>();
+    if keyboards.is_empty() {
+        eprintln!("Keyboard not found");
+        std::process::exit(1);
+    }
+    let stop_chord = crate::DEFAULT_EMERGENCY_STOP_CHORD;
+    println!(
+        "Type normally for {} minutes. Press {stop_chord:?} to finish early.",
+        duration.as_secs() / 60,
+    );
+    let (tx, rx) = channel();
+    read_devices(keyboards.into_iter(), tx);
+    let end = Instant::now() + duration;
+    let mut stats = Stats::default();
+    let mut pressed_keys = HashSet::default();
+    loop {
+        let input_event = match rx.recv_timeout(end.saturating_duration_since(Instant::now())) {
+            Ok(Event::Input(_, input_event)) => input_event,
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+        };
+        let InputEventKind::Key(key) = input_event.kind() else {
+            continue;
+        };
+        stats.add(&input_event);
+        match input_event.value() {
+            0 => {
+                pressed_keys.remove(&key);
+            }
+            1 => {
+                pressed_keys.insert(key);
+                if completes_chord(&stop_chord, key, &pressed_keys) {
+                    break;
+                }
+            }
+            _ => (),
+        }
+    }
+    print!("{}", stats.report());
+}
+
+/// The report of [`diagnose_chattering`] for the key events of a trace,
+/// e.g. one recorded by [`record_trace`](crate::record_trace).
+pub fn chattering_report(trace: &[InputEvent]) -> String {
+    let mut stats = Stats::default();
+    for e in trace {
+        stats.add(e);
+    }
+    stats.report()
+}
+
+#[derive(Default)]
+struct Stats(HashMap<Key, KeyStats>);
+
+impl Stats {
+    fn add(&mut self, input_event: &InputEvent) {
+        let InputEventKind::Key(key) = input_event.kind() else {
+            return;
+        };
+        let s = self.0.entry(key).or_default();
+        match input_event.value() {
+            0 => s.last_release = Some(input_event.timestamp()),
+            1 => {
+                s.presses += 1;
+                if let Some(release) = s.last_release {
+                    let ms = input_event
+                        .timestamp()
+                        .duration_since(release)
+                        .unwrap_or_default()
+                        .as_millis();
+                    let bucket = BUCKETS.iter().position(|b| ms < *b).unwrap();
+                    s.histogram[bucket] += 1;
+                    if ms < CHATTER_THRESHOLD {
+                        s.max_chatter = s.max_chatter.max(Some(ms));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Histograms of the keys pressed again, the most chattering first,
+    /// and the suggested windows.
+    fn report(&self) -> String {
+        let mut keys = self
+            .0
+            .iter()
+            .filter(|(_, s)| s.histogram.iter().sum::<usize>() != 0)
+            .collect::<Vec<_>>();
+        keys.sort_by_key(|(k, s)| (std::cmp::Reverse(s.chatters()), k.code()));
+        let mut report = String::new();
+        for (key, s) in &keys {
+            s.write(**key, &mut report);
+        }
+        let suggestions = keys
+            .iter()
+            .filter_map(|(k, s)| s.suggested_window().map(|w| format!("({k:?}, {w})")))
+            .collect::<Vec<_>>();
+        if suggestions.is_empty() {
+            report += "No chattering found.\n";
+        } else {
+            report += "Suggested debounce windows:\n";
+            let _ = writeln!(report, "    per_key: vec![{}],", suggestions.join(", "));
+        }
+        report
+    }
+}
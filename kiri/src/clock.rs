@@ -0,0 +1,127 @@
+use evdev::Device;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// `EVIOCSCLOCKID`, `_IOW('E', 0xa0, int)`.
+const EVIOCSCLOCKID: libc::c_ulong = 0x400445a0;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+/// The clock of a [`Simulator`](crate::Simulator), which only moves when told to.
+struct Simulated {
+    now: SystemTime,
+    /// Actions of [`Clock::at`] waiting for `now` to reach their deadline.
+    waiting: Vec<(SystemTime, Action)>,
+    /// Number of actions run so far.
+    fired: usize,
+}
+
+/// The clock the layers of a stack read and set their timers on:
+/// the real one by default, or the simulated one of a [`Simulator`](crate::Simulator).
+#[derive(Clone, Default)]
+pub(crate) struct Clock(Option<Arc<Mutex<Simulated>>>);
+
+/// The current time on the clock of the event timestamps.
+///
+/// The devices stamp events with `CLOCK_MONOTONIC` (see [`use_monotonic`]),
+/// which [`evdev::InputEvent::timestamp`] counts from `UNIX_EPOCH`,
+/// so this does the same. Times from here and from events can be compared.
+pub(crate) fn now() -> SystemTime {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+impl Clock {
+    /// A clock starting at [`now`] which only changes by [`Self::advance`].
+    pub fn simulated() -> Clock {
+        Clock(Some(Arc::new(Mutex::new(Simulated {
+            now: now(),
+            waiting: Vec::new(),
+            fired: 0,
+        }))))
+    }
+
+    pub fn now(&self) -> SystemTime {
+        match &self.0 {
+            Some(simulated) => simulated.lock().unwrap().now,
+            None => now(),
+        }
+    }
+
+    /// Run `action` once [`Self::now`] reaches `deadline`.
+    /// It runs on another thread, or while advancing a simulated clock.
+    pub fn at(&self, deadline: SystemTime, action: impl FnOnce() + Send + 'static) {
+        let Some(simulated) = &self.0 else {
+            thread::spawn(move || {
+                if let Ok(d) = deadline.duration_since(now()) {
+                    thread::sleep(d);
+                }
+                action();
+            });
+            return;
+        };
+        let mut s = simulated.lock().unwrap();
+        if deadline > s.now {
+            s.waiting.push((deadline, Box::new(action)));
+        } else {
+            s.fired += 1;
+            drop(s);
+            action();
+        }
+    }
+
+    fn simulation(&self) -> &Mutex<Simulated> {
+        self.0.as_ref().expect("the clock is not simulated")
+    }
+
+    /// Move the simulated clock forward to `time`, running the actions due on the way.
+    pub fn advance(&self, time: SystemTime) {
+        loop {
+            let mut s = self.simulation().lock().unwrap();
+            let next = s
+                .waiting
+                .iter()
+                .enumerate()
+                .filter(|(_, (deadline, _))| *deadline <= time)
+                .min_by_key(|(_, (deadline, _))| *deadline)
+                .map(|(i, _)| i);
+            let Some(i) = next else {
+                s.now = s.now.max(time);
+                return;
+            };
+            let (deadline, action) = s.waiting.swap_remove(i);
+            s.now = s.now.max(deadline);
+            s.fired += 1;
+            drop(s);
+            action();
+        }
+    }
+
+    /// The earliest deadline of the actions waiting on the simulated clock.
+    pub fn next_deadline(&self) -> Option<SystemTime> {
+        let s = self.simulation().lock().unwrap();
+        s.waiting.iter().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Number of actions run on the simulated clock so far.
+    pub fn fired(&self) -> usize {
+        self.simulation().lock().unwrap().fired
+    }
+}
+
+/// Make `device` stamp its events with `CLOCK_MONOTONIC`, which
+/// does not jump when the wall clock is set.
+pub(crate) fn use_monotonic(device: &Device) -> io::Result<()> {
+    let clock: libc::c_int = libc::CLOCK_MONOTONIC;
+    if unsafe { libc::ioctl(device.as_raw_fd(), EVIOCSCLOCKID as _, &clock) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,457 @@
+use crate::pointer::pointer_key;
+use crate::read_keys::{
+    AddLayer, KeyInput, KeyRecorder, LayerContext, PairRemapEntry, RemapLayer, SingleRemapEntry,
+    ToKeyRecorder,
+};
+use evdev::Key;
+use rustc_hash::FxHashSet as HashSet;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Mutex, OnceLock};
+
+/// A state of a layer defined at run time, e.g. in a config file.
+/// Names are interned, so comparing and hashing are as cheap as for an enum.
+#[derive(Clone, Copy)]
+pub struct StateName(&'static str);
+
+type Names = OnceLock<Mutex<HashSet<&'static str>>>;
+
+static STATE_NAMES: Names = OnceLock::new();
+
+/// Names of the layers of config files, which are `&'static str` like the
+/// names of layers defined in code.
+static LAYER_NAMES: Names = OnceLock::new();
+
+/// `name` as it is kept in `names`. Each distinct name is leaked once,
+/// so reloading a config file does not leak the names it had before.
+fn intern(names: &'static Names, name: &str) -> &'static str {
+    let mut names = names.get_or_init(Default::default).lock().unwrap();
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name = Box::leak(name.to_string().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+fn layer_name(name: &str) -> &'static str {
+    intern(&LAYER_NAMES, name)
+}
+
+impl StateName {
+    /// The name lives as long as the process, like the layers using it.
+    pub fn new(name: &str) -> Self {
+        Self(intern(&STATE_NAMES, name))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl PartialEq for StateName {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for StateName {}
+
+impl Hash for StateName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ptr().hash(state)
+    }
+}
+
+impl Debug for StateName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    layer: Vec<LayerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum LayerConfig {
+    Modifier(ModifierLayerConfig),
+    States(StateLayerConfig),
+}
+
+impl LayerConfig {
+    fn name(&self) -> &str {
+        match self {
+            LayerConfig::Modifier(l) => &l.name,
+            LayerConfig::States(l) => &l.name,
+        }
+    }
+
+    fn to_layer(&self) -> Result<RemapLayer<StateName>, String> {
+        match self {
+            LayerConfig::Modifier(l) => l.to_layer(),
+            LayerConfig::States(l) => l.to_layer(),
+        }
+    }
+}
+
+/// A layer with arbitrary states and entries,
+/// like a `RemapLayer` written in Rust.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateLayerConfig {
+    name: String,
+    /// The first one is the initial state.
+    states: Vec<String>,
+    #[serde(default)]
+    entry: Vec<SingleEntryConfig>,
+    #[serde(default)]
+    pair: Vec<PairEntryConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SingleEntryConfig {
+    /// Conditions. The entry applies in each of these states.
+    states: Vec<String>,
+    /// `"A"` or `"press A"` for a press, `"release A"` for a release.
+    input: String,
+    /// Each item is `"press A"`, `"release A"` or a tap like `"LEFTCTRL+A"`.
+    #[serde(default)]
+    output: Vec<String>,
+    /// Next state. Stays in the state if omitted.
+    transition: Option<String>,
+}
+
+/// Two keys pressed within `threshold` milli sec. in any order.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PairEntryConfig {
+    states: Vec<String>,
+    input: [String; 2],
+    #[serde(default)]
+    output: Vec<String>,
+    transition: Option<String>,
+    threshold: u32,
+    /// Hold the output while both keys are held.
+    #[serde(default)]
+    hold: bool,
+}
+
+/// A layer active while `trigger` is held.
+/// Holding the key of a sub-mode as well switches to that sub-mode
+/// until the key is released.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModifierLayerConfig {
+    name: String,
+    trigger: String,
+    /// Name of the state while only the trigger is held.
+    #[serde(default = "default_mode")]
+    mode: String,
+    /// Input key to output, like `"LEFTCTRL+S"`.
+    #[serde(default)]
+    mappings: BTreeMap<String, String>,
+    #[serde(default)]
+    sub_mode: Vec<SubModeConfig>,
+}
+
+fn default_mode() -> String {
+    "Active".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubModeConfig {
+    name: String,
+    key: String,
+    #[serde(default)]
+    mappings: BTreeMap<String, String>,
+}
+
+/// Parse `KEY_A`, `A`, `BTN_LEFT` or a key moving the pointer like `POINTER_UP`.
+fn parse_key(name: &str) -> Result<Key, String> {
+    name.parse()
+        .or_else(|_| format!("KEY_{name}").parse())
+        .or_else(|_| pointer_key(name).ok_or(()))
+        .map_err(|_| format!("unknown key {name:?}"))
+}
+
+/// Parse `"press A"`, `"release A"` or `"A"`, which is a press.
+fn parse_key_input(input: &str) -> Result<KeyInput, String> {
+    match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["press", key] | [key] => Ok(KeyInput::press(parse_key(key)?)),
+        ["release", key] => Ok(KeyInput::release(parse_key(key)?)),
+        _ => Err(format!("invalid input {input:?}")),
+    }
+}
+
+/// Parse output items, which are `"press A"`, `"release A"` or taps like `"LEFTCTRL+A"`.
+fn parse_outputs(outputs: &[String]) -> Result<Vec<KeyInput>, String> {
+    let mut keys = Vec::new();
+    for output in outputs {
+        match output.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["press", key] => keys.push(KeyInput::press(parse_key(key)?)),
+            ["release", key] => keys.push(KeyInput::release(parse_key(key)?)),
+            _ => keys.append(&mut parse_output(output)?),
+        }
+    }
+    Ok(keys)
+}
+
+/// Parse `MOD+MOD+KEY` into a tap of `KEY` while the modifiers are held.
+fn parse_output(output: &str) -> Result<Vec<KeyInput>, String> {
+    let keys = output
+        .split('+')
+        .map(|k| parse_key(k.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (key, modifiers) = keys.split_last().unwrap();
+    Ok(modifiers
+        .iter()
+        .map(|m| KeyInput::press(*m))
+        .chain([KeyInput::press(*key), KeyInput::release(*key)])
+        .chain(modifiers.iter().map(|m| KeyInput::release(*m)))
+        .collect())
+}
+
+impl StateLayerConfig {
+    fn to_layer(&self) -> Result<RemapLayer<StateName>, String> {
+        let states = self
+            .states
+            .iter()
+            .map(|s| StateName::new(s))
+            .collect::<Vec<_>>();
+        let initial_state = *states
+            .first()
+            .ok_or_else(|| "no states are defined".to_string())?;
+        let state = |name: &String| {
+            let s = StateName::new(name);
+            if states.contains(&s) {
+                Ok(s)
+            } else {
+                Err(format!("state {name:?} is not in `states`"))
+            }
+        };
+        let conditions = |names: &[String]| names.iter().map(state).collect::<Result<Vec<_>, _>>();
+        let mut single_remap_entries = Vec::new();
+        for e in &self.entry {
+            let input = parse_key_input(&e.input)?;
+            let output = parse_outputs(&e.output)?;
+            let transition = e.transition.as_ref().map(state).transpose()?;
+            for c in conditions(&e.states)? {
+                single_remap_entries.push(SingleRemapEntry {
+                    condition: c,
+                    input,
+                    output: output.clone(),
+                    transition: transition.unwrap_or(c),
+                });
+            }
+        }
+        let mut pair_remap_entries = Vec::new();
+        for e in &self.pair {
+            let input = [parse_key(&e.input[0])?, parse_key(&e.input[1])?].map(KeyInput::press);
+            let output = parse_outputs(&e.output)?;
+            let transition = e.transition.as_ref().map(state).transpose()?;
+            for c in conditions(&e.states)? {
+                pair_remap_entries.extend(
+                    PairRemapEntry {
+                        condition: c,
+                        input,
+                        output: output.clone(),
+                        transition: transition.unwrap_or(c),
+                        threshold: e.threshold,
+                        hold: e.hold,
+                    }
+                    .order_insensitive(),
+                );
+            }
+        }
+        Ok(RemapLayer {
+            pair_remap_entries,
+            single_remap_entries,
+            tap_dance_entries: Vec::new(),
+            single_pattern_entries: Vec::new(),
+            pair_pattern_entries: Vec::new(),
+            layer_name: layer_name(&self.name),
+            initial_state,
+        })
+    }
+}
+
+impl ModifierLayerConfig {
+    fn to_layer(&self) -> Result<RemapLayer<StateName>, String> {
+        let normal = StateName::new("Normal");
+        let mode = StateName::new(&self.mode);
+        let trigger = parse_key(&self.trigger)?;
+        let sub_modes = self
+            .sub_mode
+            .iter()
+            .map(|s| Ok((StateName::new(&s.name), parse_key(&s.key)?, &s.mappings)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let active = [mode]
+            .into_iter()
+            .chain(sub_modes.iter().map(|(s, _, _)| *s))
+            .collect::<Vec<_>>();
+        let mut states = vec![normal];
+        for s in &active {
+            if states.contains(s) {
+                return Err(format!("state {s:?} is defined twice"));
+            }
+            states.push(*s);
+        }
+        let entry = |condition, input, output, transition| SingleRemapEntry {
+            condition,
+            input,
+            output,
+            transition,
+        };
+        let mut entries = Vec::new();
+        // Pressing the trigger activates the layer and its repeats are ignored.
+        entries.push(entry(normal, KeyInput::press(trigger), Vec::new(), mode));
+        for s in &active {
+            entries.push(entry(*s, KeyInput::press(trigger), Vec::new(), *s));
+        }
+        for s in &states {
+            entries.push(entry(*s, KeyInput::release(trigger), Vec::new(), normal));
+        }
+        for (sub_mode, key, _) in &sub_modes {
+            for s in &active {
+                entries.push(entry(*s, KeyInput::press(*key), Vec::new(), *sub_mode));
+            }
+            entries.push(entry(*sub_mode, KeyInput::release(*key), Vec::new(), mode));
+        }
+        for (state, mappings) in [(mode, &self.mappings)]
+            .into_iter()
+            .chain(sub_modes.iter().map(|(s, _, m)| (*s, *m)))
+        {
+            for (input, output) in mappings {
+                let input = KeyInput::press(parse_key(input)?);
+                if entries
+                    .iter()
+                    .any(|e| e.condition == state && e.input == input)
+                {
+                    return Err(format!("{input:?} is used twice in {state:?}"));
+                }
+                entries.push(entry(
+                    state,
+                    input,
+                    parse_outputs(std::slice::from_ref(output))?,
+                    state,
+                ));
+            }
+        }
+        Ok(RemapLayer {
+            pair_remap_entries: Vec::new(),
+            single_remap_entries: entries,
+            tap_dance_entries: Vec::new(),
+            single_pattern_entries: Vec::new(),
+            pair_pattern_entries: Vec::new(),
+            layer_name: layer_name(&self.name),
+            initial_state: normal,
+        })
+    }
+}
+
+/// Build the layers defined in a config file.
+pub fn parse_layers(source: &str) -> Result<Vec<RemapLayer<StateName>>, String> {
+    let config: ConfigFile = toml::from_str(source).map_err(|e| e.to_string())?;
+    config
+        .layer
+        .iter()
+        .map(|l| l.to_layer().map_err(|e| format!("{}: {e}", l.name())))
+        .collect()
+}
+
+pub fn load_layers(path: &Path) -> Result<Vec<RemapLayer<StateName>>, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    parse_layers(&source).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+enum LayersSource {
+    Path(PathBuf),
+    Text(&'static str),
+}
+
+/// The layers of a config file, built when the first layer stack is.
+/// With `KeyConfig::run_as` that is after switching users, so the file is read
+/// and parsed without root. An invalid file stops the remapper.
+pub struct LayersFile {
+    source: LayersSource,
+    layers: OnceLock<Vec<RemapLayer<StateName>>>,
+}
+
+impl LayersFile {
+    pub fn load(path: impl Into<PathBuf>) -> LayersFile {
+        LayersFile {
+            source: LayersSource::Path(path.into()),
+            layers: OnceLock::new(),
+        }
+    }
+
+    pub fn parse(source: &'static str) -> LayersFile {
+        LayersFile {
+            source: LayersSource::Text(source),
+            layers: OnceLock::new(),
+        }
+    }
+
+    fn layers(&self) -> &[RemapLayer<StateName>] {
+        self.layers.get_or_init(|| {
+            let layers = match &self.source {
+                LayersSource::Path(path) => load_layers(path),
+                LayersSource::Text(source) => parse_layers(source),
+            };
+            layers.unwrap_or_else(|e| {
+                eprintln!("{e}");
+                exit(1)
+            })
+        })
+    }
+}
+
+/// The layers of a [`LayersFile`] followed by other layers.
+pub struct LayersFileLayers<Tail>(LayersFile, Tail);
+
+impl<T: ToKeyRecorder> ToKeyRecorder for LayersFileLayers<T> {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        self.0
+            .layers()
+            .iter()
+            .rev()
+            .fold(self.1.to_key_recorder(context), |tail, layer| {
+                KeyRecorder::new(layer.clone(), tail, context)
+            })
+    }
+}
+
+impl ToKeyRecorder for LayersFile {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        self.layers().to_vec().to_key_recorder(context)
+    }
+}
+
+impl AddLayer for LayersFile {
+    type LayerAdded<A> = LayersFileLayers<A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        LayersFileLayers(self, tail)
+    }
+}
+
+impl<Tail: AddLayer> AddLayer for LayersFileLayers<Tail> {
+    type LayerAdded<A> = LayersFileLayers<Tail::LayerAdded<A>>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        LayersFileLayers(self.0, self.1.add_layer(tail))
+    }
+}
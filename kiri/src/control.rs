@@ -0,0 +1,174 @@
+use crate::Event;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+use std::{fs, thread};
+
+/// A command sent to the control socket, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+    Pause,
+    Resume,
+    TogglePause,
+    Status,
+    /// Show the state of every layer.
+    Layers,
+    SetState {
+        layer: String,
+        state: String,
+    },
+    SetEnabled {
+        layer: String,
+        enabled: bool,
+    },
+    /// Restart the process to load the config again.
+    Reload,
+    /// Print the keys emitted from now on.
+    Events,
+    /// Show the histogram of latencies, and clear it if `reset`.
+    Latency {
+        reset: bool,
+    },
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["pause"] => Ok(Self::Pause),
+            ["resume"] => Ok(Self::Resume),
+            ["toggle"] => Ok(Self::TogglePause),
+            ["status"] => Ok(Self::Status),
+            ["layers"] => Ok(Self::Layers),
+            ["set-state", layer @ .., state] if !layer.is_empty() => Ok(Self::SetState {
+                layer: layer.join(" "),
+                state: state.to_string(),
+            }),
+            ["enable", layer @ ..] if !layer.is_empty() => Ok(Self::SetEnabled {
+                layer: layer.join(" "),
+                enabled: true,
+            }),
+            ["disable", layer @ ..] if !layer.is_empty() => Ok(Self::SetEnabled {
+                layer: layer.join(" "),
+                enabled: false,
+            }),
+            ["reload"] => Ok(Self::Reload),
+            ["events"] => Ok(Self::Events),
+            ["latency"] => Ok(Self::Latency { reset: false }),
+            ["latency", "reset"] => Ok(Self::Latency { reset: true }),
+            _ => Err(format!("unknown command: {line:?}")),
+        }
+    }
+}
+
+/// An answer to a command.
+/// Each answer is written as lines followed by an empty line.
+pub(crate) enum Reply {
+    Lines(Vec<String>),
+    /// Lines sent until the client disconnects.
+    Stream(Receiver<String>),
+    /// The last answer on the connection.
+    /// The sender is notified after it has been written.
+    Last(Vec<String>, Sender<()>),
+}
+
+impl Reply {
+    pub fn line(line: impl Into<String>) -> Self {
+        Self::Lines(vec![line.into()])
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub command: Command,
+    pub reply: Sender<Reply>,
+}
+
+/// Start accepting connections on `path`.
+/// Each line received is sent to `tx` as a command.
+pub(crate) fn listen(path: &Path, tx: Sender<Event>) -> io::Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    log::info!("Listening on {}", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_client(stream, tx) {
+                            log::warn!("control socket: {e}");
+                        }
+                    });
+                }
+                Err(e) => log::error!("control socket: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn write_lines(writer: &mut impl Write, lines: &[String]) -> io::Result<()> {
+    for line in lines {
+        writeln!(writer, "{line}")?;
+    }
+    writeln!(writer)?;
+    writer.flush()
+}
+
+fn handle_client(stream: UnixStream, tx: Sender<Event>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let command = match Command::parse(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                write_lines(&mut writer, &[format!("error: {e}")])?;
+                continue;
+            }
+        };
+        let (reply_tx, reply_rx) = channel();
+        tx.send(Event::Request(Request {
+            command,
+            reply: reply_tx,
+        }))
+        .unwrap();
+        match reply_rx.recv() {
+            Ok(Reply::Lines(lines)) => write_lines(&mut writer, &lines)?,
+            Ok(Reply::Stream(rx)) => {
+                for line in rx {
+                    writeln!(writer, "{line}")?;
+                }
+                return Ok(());
+            }
+            Ok(Reply::Last(lines, written)) => {
+                let result = write_lines(&mut writer, &lines);
+                let _ = written.send(());
+                return result;
+            }
+            Err(_) => write_lines(&mut writer, &["error: no reply".to_string()])?,
+        }
+    }
+    Ok(())
+}
+
+/// Send `command` to the control socket at `path` and copy the answer to `out`.
+pub fn send_command(path: &Path, command: &str, out: &mut impl Write) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{command}")?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        writeln!(out, "{line}")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// How long to wait for the answer to be written before restarting.
+pub(crate) const RELOAD_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
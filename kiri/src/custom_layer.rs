@@ -0,0 +1,172 @@
+use crate::clock::Clock;
+use crate::hooks::StateChange;
+use crate::read_keys::{
+    AddLayer, Inbox, KeyInput, KeyReceiver, KeyRecorder, KeyRecorderBehavior, LayerContext,
+    LayerControl, LayerStatus, ToKeyRecorder,
+};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+/// A layer whose behavior cannot be written as entries of a `RemapLayer`.
+/// It runs on its own thread like a `RemapLayer`, which also handles
+/// enabling, reporting state changes and passing control messages on.
+pub(crate) trait CustomLayer: Send + 'static {
+    fn name(&self) -> &'static str;
+    fn state(&self) -> String;
+    /// Force the state, e.g. from the control socket.
+    fn set_state(&mut self, state: &str) -> Result<(), String>;
+    fn send_key(&mut self, key: KeyInput, time: SystemTime, timer: &Timer);
+    /// A timer set with [`Timer::set`] has expired.
+    fn timeout(&mut self, _key: KeyInput, _time: SystemTime, _timer: &Timer) {}
+    /// Release the keys held by this layer and go back to the initial state.
+    fn reset(&mut self);
+    fn key_receiver(&mut self) -> &mut dyn KeyReceiver;
+}
+
+/// Configuration of a [`CustomLayer`].
+pub(crate) trait ToCustomLayer {
+    fn to_custom_layer(
+        &self,
+        key_receiver: impl KeyReceiver + 'static,
+        context: &LayerContext,
+    ) -> impl CustomLayer;
+}
+
+pub(crate) struct Timer {
+    tx: Sender<KeyRecorderBehavior>,
+    clock: Clock,
+}
+
+impl Timer {
+    /// Call [`CustomLayer::timeout`] with `key` and `time` once `delay` has passed since `time`.
+    pub fn set(&self, key: KeyInput, time: SystemTime, delay: Duration) {
+        let tx = self.tx.clone();
+        let deadline = time + delay;
+        self.clock.at(deadline, move || {
+            let _ = tx.send(KeyRecorderBehavior::FireSpecificWaitingKey(
+                (key, time),
+                deadline,
+            ));
+        });
+    }
+}
+
+fn control(message: LayerControl, layer: &mut impl CustomLayer, enabled: &mut bool) {
+    match message {
+        LayerControl::Query(mut statuses, reply) => {
+            statuses.push(LayerStatus {
+                name: layer.name(),
+                state: layer.state(),
+                enabled: *enabled,
+            });
+            layer
+                .key_receiver()
+                .control(LayerControl::Query(statuses, reply));
+        }
+        LayerControl::SetState {
+            layer: name,
+            state,
+            reply,
+        } if name == layer.name() => {
+            let _ = reply.send(layer.set_state(&state));
+        }
+        LayerControl::SetEnabled {
+            layer: name,
+            enabled: e,
+            reply,
+        } if name == layer.name() => {
+            if e != *enabled {
+                if !e {
+                    layer.reset();
+                }
+                log::info!(
+                    "[{}] {}",
+                    layer.name(),
+                    if e { "enabled" } else { "disabled" }
+                );
+                *enabled = e;
+            }
+            let _ = reply.send(Ok(()));
+        }
+        message => layer.key_receiver().control(message),
+    }
+}
+
+fn run(mut layer: impl CustomLayer, timer: Timer, rx: Inbox, state_changes: Sender<StateChange>) {
+    let name = layer.name();
+    let mut state = layer.state();
+    let _ = state_changes.send(StateChange {
+        layer: name,
+        previous: None,
+        state: state.clone(),
+    });
+    let mut enabled = true;
+    for received in rx {
+        match received {
+            KeyRecorderBehavior::SendKey((key, time)) if !enabled => {
+                layer.key_receiver().send_key(key, time)
+            }
+            KeyRecorderBehavior::SendKey((key, time)) => layer.send_key(key, time, &timer),
+            KeyRecorderBehavior::FireSpecificWaitingKey((key, time), _) => {
+                if enabled {
+                    layer.timeout(key, time, &timer)
+                }
+            }
+            KeyRecorderBehavior::Reset => {
+                layer.reset();
+                layer.key_receiver().reset();
+            }
+            KeyRecorderBehavior::Stop => {
+                layer.reset();
+                layer.key_receiver().stop();
+                break;
+            }
+            KeyRecorderBehavior::Control(message) => control(message, &mut layer, &mut enabled),
+        }
+        let new_state = layer.state();
+        if new_state != state {
+            log::debug!("[{name}] state : {state} =====> {new_state}");
+            let _ = state_changes.send(StateChange {
+                layer: name,
+                previous: Some(std::mem::replace(&mut state, new_state.clone())),
+                state: new_state,
+            });
+        }
+    }
+}
+
+fn spawn(
+    config: &impl ToCustomLayer,
+    key_receiver: impl KeyReceiver + 'static,
+    context: &LayerContext,
+) -> KeyRecorder {
+    let layer = config.to_custom_layer(key_receiver, context);
+    let state_changes = context.state_changes.clone();
+    let clock = context.clock.clone();
+    KeyRecorder::spawn(layer.name(), move |tx, rx| {
+        run(layer, Timer { tx, clock }, rx, state_changes)
+    })
+}
+
+/// A custom layer followed by other layers.
+pub struct CustomLayers<L, Tail>(pub(crate) L, pub(crate) Tail);
+
+impl<L: ToCustomLayer> ToKeyRecorder for L {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        spawn(self, context.writer.handle("layers"), context)
+    }
+}
+
+impl<L: ToCustomLayer, T: ToKeyRecorder> ToKeyRecorder for CustomLayers<L, T> {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        spawn(&self.0, self.1.to_key_recorder(context), context)
+    }
+}
+
+impl<L, Tail: AddLayer> AddLayer for CustomLayers<L, Tail> {
+    type LayerAdded<A> = CustomLayers<L, Tail::LayerAdded<A>>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        CustomLayers(self.0, self.1.add_layer(tail))
+    }
+}
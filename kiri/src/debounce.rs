@@ -0,0 +1,216 @@
+use crate::clock;
+use crate::{DeviceId, Event};
+use evdev::{InputEvent, InputEventKind, Key};
+use rustc_hash::FxHashMap as HashMap;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebounceAlgorithm {
+    /// Send a change at once and ignore further changes of the key until
+    /// the window has passed. Adds no latency.
+    Eager,
+    /// Send a change after the key has stayed in the new state for the window.
+    /// Also filters noise on a key that is not being touched.
+    Deferred,
+}
+
+/// Suppression of chattering, done before all layers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebounceConfig {
+    pub algorithm: DebounceAlgorithm,
+    /// Window for keys not listed in `per_key`. milli sec. 0 disables debouncing.
+    pub window: u32,
+    /// Windows for specific keys. milli sec.
+    pub per_key: Vec<(Key, u32)>,
+}
+
+impl DebounceConfig {
+    fn window(&self, key: Key) -> Duration {
+        let ms = self
+            .per_key
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map_or(self.window, |(_, w)| *w);
+        Duration::from_millis(ms as u64)
+    }
+}
+
+#[derive(Debug)]
+struct KeyState {
+    /// State last sent to the layers.
+    reported: bool,
+    /// State last read from the device.
+    raw: bool,
+    /// The event which made `raw`.
+    last_event: InputEvent,
+    /// When the window ends, on the clock of the event timestamps.
+    deadline: Option<SystemTime>,
+}
+
+pub(crate) struct Debouncer {
+    config: DebounceConfig,
+    /// Keys of each device.
+    keys: HashMap<(DeviceId, Key), KeyState>,
+    tx: Sender<Event>,
+}
+
+impl Debouncer {
+    /// A debouncer which passes the events it receives on to `tx`.
+    pub(crate) fn new(config: DebounceConfig, tx: Sender<Event>) -> Debouncer {
+        Debouncer {
+            config,
+            keys: HashMap::default(),
+            tx,
+        }
+    }
+
+    fn report(&mut self, id: DeviceId, key: Key) {
+        let s = self.keys.get_mut(&(id, key)).unwrap();
+        s.reported = s.raw;
+        self.tx.send(Event::Input(id, s.last_event)).unwrap();
+    }
+
+    /// Windows are counted from the timestamp of `event`, so a delay in reading
+    /// the events does not change which ones are suppressed.
+    pub(crate) fn receive(&mut self, id: DeviceId, event: InputEvent) {
+        let key = match event.kind() {
+            InputEventKind::Key(key) => key,
+            _ => {
+                self.tx.send(Event::Input(id, event)).unwrap();
+                return;
+            }
+        };
+        let window = self.config.window(key);
+        let now = event.timestamp();
+        let pressed = event.value() != 0;
+        let s = self.keys.entry((id, key)).or_insert(KeyState {
+            reported: false,
+            raw: false,
+            last_event: event,
+            deadline: None,
+        });
+        if event.value() == 2 {
+            if s.raw && s.reported {
+                self.tx.send(Event::Input(id, event)).unwrap();
+            }
+            return;
+        }
+        let interval = event
+            .timestamp()
+            .duration_since(s.last_event.timestamp())
+            .unwrap_or_default();
+        s.raw = pressed;
+        s.last_event = event;
+        if window.is_zero() {
+            self.report(id, key);
+            return;
+        }
+        match self.config.algorithm {
+            DebounceAlgorithm::Eager => {
+                if s.deadline.is_some() {
+                    log::info!(
+                        "[debounce] suppressed {:?} {} {}ms after the last change",
+                        key,
+                        if pressed { "press" } else { "release" },
+                        interval.as_millis()
+                    );
+                } else if s.raw != s.reported {
+                    s.deadline = Some(now + window);
+                    self.report(id, key);
+                }
+            }
+            DebounceAlgorithm::Deferred => {
+                if s.raw == s.reported && s.deadline.is_some() {
+                    log::info!(
+                        "[debounce] suppressed {:?} {} and {} {}ms apart",
+                        key,
+                        if pressed { "release" } else { "press" },
+                        if pressed { "press" } else { "release" },
+                        interval.as_millis()
+                    );
+                    s.deadline = None;
+                } else {
+                    s.deadline = Some(now + window);
+                }
+            }
+        }
+    }
+
+    /// Handle the keys whose window has passed by `now`.
+    pub(crate) fn expire(&mut self, now: SystemTime) {
+        let expired = self
+            .keys
+            .iter()
+            .filter(|(_, s)| s.deadline.is_some_and(|d| d <= now))
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        for (id, key) in expired {
+            let s = self.keys.get_mut(&(id, key)).unwrap();
+            s.deadline = None;
+            if s.raw != s.reported {
+                if self.config.algorithm == DebounceAlgorithm::Eager {
+                    s.deadline = Some(now + self.config.window(key));
+                }
+                self.report(id, key);
+            }
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<SystemTime> {
+        self.keys.values().filter_map(|s| s.deadline).min()
+    }
+
+    /// Whether `event` completes `chord` on the keys as read from the devices,
+    /// whether or not the changes have been passed on yet.
+    fn completes_raw_chord(&self, chord: &[Key], event: InputEvent) -> bool {
+        let InputEventKind::Key(key) = event.kind() else {
+            return false;
+        };
+        event.value() == 1
+            && chord.contains(&key)
+            && chord
+                .iter()
+                .all(|k| self.keys.iter().any(|((_, held), s)| held == k && s.raw))
+    }
+}
+
+/// Start a thread which debounces the events sent to the returned sender
+/// and passes them to `tx`. Completing `emergency_stop_chord` sends
+/// [`Event::EmergencyStop`] at once.
+pub(crate) fn spawn(
+    config: DebounceConfig,
+    emergency_stop_chord: Vec<Key>,
+    tx: Sender<Event>,
+) -> Sender<Event> {
+    let (debounce_tx, rx) = channel();
+    let mut debouncer = Debouncer::new(config, tx);
+    thread::spawn(move || loop {
+        let received = match debouncer.next_deadline() {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.duration_since(clock::now()).unwrap_or_default())
+            }
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Event::Input(id, event)) => {
+                // Windows which ended before the event happened come first.
+                debouncer.expire(event.timestamp());
+                debouncer.receive(id, event);
+                if debouncer.completes_raw_chord(&emergency_stop_chord, event) {
+                    debouncer.tx.send(Event::EmergencyStop).unwrap();
+                }
+            }
+            Ok(Event::Removed(id)) => {
+                debouncer.keys.retain(|(i, _), _| *i != id);
+                debouncer.tx.send(Event::Removed(id)).unwrap();
+            }
+            Ok(event) => debouncer.tx.send(event).unwrap(),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        debouncer.expire(clock::now());
+    });
+    debounce_tx
+}
@@ -0,0 +1,375 @@
+use crate::custom_layer::{CustomLayer, CustomLayers, Timer, ToCustomLayer};
+use crate::read_keys::{AddLayer, KeyInput, KeyInputKind, KeyReceiver, LayerContext};
+use evdev::Key;
+use rustc_hash::FxHashSet as HashSet;
+use std::time::SystemTime;
+
+/// A key and the characters it types without and with shift.
+type CharKey = (Key, char, Option<char>);
+
+const US_KEYS: &[CharKey] = &[
+    (Key::KEY_A, 'a', Some('A')),
+    (Key::KEY_B, 'b', Some('B')),
+    (Key::KEY_C, 'c', Some('C')),
+    (Key::KEY_D, 'd', Some('D')),
+    (Key::KEY_E, 'e', Some('E')),
+    (Key::KEY_F, 'f', Some('F')),
+    (Key::KEY_G, 'g', Some('G')),
+    (Key::KEY_H, 'h', Some('H')),
+    (Key::KEY_I, 'i', Some('I')),
+    (Key::KEY_J, 'j', Some('J')),
+    (Key::KEY_K, 'k', Some('K')),
+    (Key::KEY_L, 'l', Some('L')),
+    (Key::KEY_M, 'm', Some('M')),
+    (Key::KEY_N, 'n', Some('N')),
+    (Key::KEY_O, 'o', Some('O')),
+    (Key::KEY_P, 'p', Some('P')),
+    (Key::KEY_Q, 'q', Some('Q')),
+    (Key::KEY_R, 'r', Some('R')),
+    (Key::KEY_S, 's', Some('S')),
+    (Key::KEY_T, 't', Some('T')),
+    (Key::KEY_U, 'u', Some('U')),
+    (Key::KEY_V, 'v', Some('V')),
+    (Key::KEY_W, 'w', Some('W')),
+    (Key::KEY_X, 'x', Some('X')),
+    (Key::KEY_Y, 'y', Some('Y')),
+    (Key::KEY_Z, 'z', Some('Z')),
+    (Key::KEY_1, '1', Some('!')),
+    (Key::KEY_2, '2', Some('@')),
+    (Key::KEY_3, '3', Some('#')),
+    (Key::KEY_4, '4', Some('$')),
+    (Key::KEY_5, '5', Some('%')),
+    (Key::KEY_6, '6', Some('^')),
+    (Key::KEY_7, '7', Some('&')),
+    (Key::KEY_8, '8', Some('*')),
+    (Key::KEY_9, '9', Some('(')),
+    (Key::KEY_0, '0', Some(')')),
+    (Key::KEY_MINUS, '-', Some('_')),
+    (Key::KEY_EQUAL, '=', Some('+')),
+    (Key::KEY_LEFTBRACE, '[', Some('{')),
+    (Key::KEY_RIGHTBRACE, ']', Some('}')),
+    (Key::KEY_BACKSLASH, '\\', Some('|')),
+    (Key::KEY_SEMICOLON, ';', Some(':')),
+    (Key::KEY_APOSTROPHE, '\'', Some('"')),
+    (Key::KEY_GRAVE, '`', Some('~')),
+    (Key::KEY_COMMA, ',', Some('<')),
+    (Key::KEY_DOT, '.', Some('>')),
+    (Key::KEY_SLASH, '/', Some('?')),
+    (Key::KEY_SPACE, ' ', Some(' ')),
+    (Key::KEY_ENTER, '\n', Some('\n')),
+    (Key::KEY_TAB, '\t', Some('\t')),
+];
+
+const JIS_KEYS: &[CharKey] = &[
+    (Key::KEY_A, 'a', Some('A')),
+    (Key::KEY_B, 'b', Some('B')),
+    (Key::KEY_C, 'c', Some('C')),
+    (Key::KEY_D, 'd', Some('D')),
+    (Key::KEY_E, 'e', Some('E')),
+    (Key::KEY_F, 'f', Some('F')),
+    (Key::KEY_G, 'g', Some('G')),
+    (Key::KEY_H, 'h', Some('H')),
+    (Key::KEY_I, 'i', Some('I')),
+    (Key::KEY_J, 'j', Some('J')),
+    (Key::KEY_K, 'k', Some('K')),
+    (Key::KEY_L, 'l', Some('L')),
+    (Key::KEY_M, 'm', Some('M')),
+    (Key::KEY_N, 'n', Some('N')),
+    (Key::KEY_O, 'o', Some('O')),
+    (Key::KEY_P, 'p', Some('P')),
+    (Key::KEY_Q, 'q', Some('Q')),
+    (Key::KEY_R, 'r', Some('R')),
+    (Key::KEY_S, 's', Some('S')),
+    (Key::KEY_T, 't', Some('T')),
+    (Key::KEY_U, 'u', Some('U')),
+    (Key::KEY_V, 'v', Some('V')),
+    (Key::KEY_W, 'w', Some('W')),
+    (Key::KEY_X, 'x', Some('X')),
+    (Key::KEY_Y, 'y', Some('Y')),
+    (Key::KEY_Z, 'z', Some('Z')),
+    (Key::KEY_1, '1', Some('!')),
+    (Key::KEY_2, '2', Some('"')),
+    (Key::KEY_3, '3', Some('#')),
+    (Key::KEY_4, '4', Some('$')),
+    (Key::KEY_5, '5', Some('%')),
+    (Key::KEY_6, '6', Some('&')),
+    (Key::KEY_7, '7', Some('\'')),
+    (Key::KEY_8, '8', Some('(')),
+    (Key::KEY_9, '9', Some(')')),
+    (Key::KEY_0, '0', None),
+    (Key::KEY_MINUS, '-', Some('=')),
+    (Key::KEY_EQUAL, '^', Some('~')),
+    (Key::KEY_LEFTBRACE, '@', Some('`')),
+    (Key::KEY_RIGHTBRACE, '[', Some('{')),
+    (Key::KEY_SEMICOLON, ';', Some('+')),
+    (Key::KEY_APOSTROPHE, ':', Some('*')),
+    (Key::KEY_BACKSLASH, ']', Some('}')),
+    (Key::KEY_COMMA, ',', Some('<')),
+    (Key::KEY_DOT, '.', Some('>')),
+    (Key::KEY_SLASH, '/', Some('?')),
+    (Key::KEY_RO, '\\', Some('_')),
+    (Key::KEY_YEN, '\\', Some('|')),
+    (Key::KEY_SPACE, ' ', Some(' ')),
+    (Key::KEY_ENTER, '\n', Some('\n')),
+    (Key::KEY_TAB, '\t', Some('\t')),
+];
+
+/// The layout the desktop uses to turn keys into characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    Us,
+    /// Japanese, with `@` next to P and `\` on `RO` and `YEN`.
+    Jis,
+}
+
+impl KeyboardLayout {
+    fn keys(self) -> &'static [CharKey] {
+        match self {
+            KeyboardLayout::Us => US_KEYS,
+            KeyboardLayout::Jis => JIS_KEYS,
+        }
+    }
+
+    fn char_of(self, key: Key, shift: bool) -> Option<char> {
+        self.keys()
+            .iter()
+            .find(|(k, _, _)| *k == key)
+            .and_then(|(_, c, shifted)| if shift { *shifted } else { Some(*c) })
+    }
+
+    /// The key typing `c` and whether it needs shift.
+    fn key_of(self, c: char) -> Option<(Key, bool)> {
+        self.keys().iter().find_map(|(k, plain, shifted)| {
+            if *plain == c {
+                Some((*k, false))
+            } else if *shifted == Some(c) {
+                Some((*k, true))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+const SHIFTS: [Key; 2] = [Key::KEY_LEFTSHIFT, Key::KEY_RIGHTSHIFT];
+
+/// Modifiers making keys shortcuts rather than characters.
+const SHORTCUT_MODIFIERS: [Key; 6] = [
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+];
+
+/// Keys which do not end an abbreviation being typed.
+const IGNORED: [Key; 2] = [Key::KEY_CAPSLOCK, Key::KEY_NUMLOCK];
+
+/// A layer replacing typed abbreviations, e.g. `;addr`, with their expansions.
+/// It watches the keys sent to it, so put it after the layers producing
+/// the typed text.
+#[derive(Debug, Clone)]
+pub struct ExpansionLayer {
+    pub layer_name: &'static str,
+    /// Used to tell the typed characters and to type the expansions.
+    /// Characters the layout has no key for are not typed.
+    pub layout: KeyboardLayout,
+    /// Abbreviations and their expansions.
+    pub abbreviations: Vec<(String, String)>,
+    /// Nothing is expanded after a press of the first key until a press of
+    /// the second, e.g. the markers sent while Japanese input is on.
+    /// Both are passed on to the next layer.
+    pub suspend_keys: Option<(Key, Key)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpansionState {
+    Active,
+    Suspended,
+}
+
+struct Expander<T> {
+    key_receiver: T,
+    layer: ExpansionLayer,
+    state: ExpansionState,
+    /// Characters typed since the last key which is not a character.
+    typed: String,
+    /// Length of the longest abbreviation in bytes. No more is kept in `typed`.
+    longest: usize,
+    /// Held modifiers including shifts.
+    modifiers: HashSet<Key>,
+    /// Keys whose releases are dropped since their presses completed an abbreviation.
+    swallowed: HashSet<Key>,
+}
+
+impl<T: KeyReceiver> Expander<T> {
+    fn set_state(&mut self, state: ExpansionState) {
+        if state != self.state {
+            self.state = state;
+            self.typed.clear();
+        }
+    }
+
+    fn tap(&mut self, key: Key, time: SystemTime) {
+        self.key_receiver.send_key(KeyInput::press(key), time);
+        self.key_receiver.send_key(KeyInput::release(key), time);
+    }
+
+    /// Erase `abbreviation` but its last character, which has not been sent,
+    /// and type `expansion`.
+    fn expand(&mut self, abbreviation: &str, expansion: &str, time: SystemTime) {
+        log::debug!("[{}] expand {abbreviation:?}", self.layer.layer_name);
+        for _ in 1..abbreviation.chars().count() {
+            self.tap(Key::KEY_BACKSPACE, time);
+        }
+        let shifts = SHIFTS
+            .into_iter()
+            .filter(|k| self.modifiers.contains(k))
+            .collect::<Vec<_>>();
+        for k in &shifts {
+            self.key_receiver.send_key(KeyInput::release(*k), time);
+        }
+        for c in expansion.chars() {
+            match self.layer.layout.key_of(c) {
+                Some((key, true)) => {
+                    self.key_receiver
+                        .send_key(KeyInput::press(Key::KEY_LEFTSHIFT), time);
+                    self.tap(key, time);
+                    self.key_receiver
+                        .send_key(KeyInput::release(Key::KEY_LEFTSHIFT), time);
+                }
+                Some((key, false)) => self.tap(key, time),
+                None => log::warn!("[{}] cannot type {c:?}", self.layer.layer_name),
+            }
+        }
+        for k in &shifts {
+            self.key_receiver.send_key(KeyInput::press(*k), time);
+        }
+        self.typed.clear();
+    }
+
+    fn type_key(&mut self, key: KeyInput, time: SystemTime) {
+        let KeyInput(k, kind) = key;
+        if kind == KeyInputKind::Release {
+            self.modifiers.remove(&k);
+            if !self.swallowed.remove(&k) {
+                self.key_receiver.send_key(key, time);
+            }
+            return;
+        }
+        if self.swallowed.contains(&k) {
+            // Repeats of the key completing an abbreviation.
+            return;
+        }
+        match self.layer.suspend_keys {
+            Some((suspend, _)) if k == suspend => self.set_state(ExpansionState::Suspended),
+            Some((_, resume)) if k == resume => self.set_state(ExpansionState::Active),
+            _ => (),
+        }
+        let shift = SHIFTS.iter().any(|s| self.modifiers.contains(s));
+        let shortcut = SHORTCUT_MODIFIERS
+            .iter()
+            .any(|m| self.modifiers.contains(m));
+        if SHIFTS.contains(&k) || SHORTCUT_MODIFIERS.contains(&k) {
+            self.modifiers.insert(k);
+        } else if k == Key::KEY_BACKSPACE && !shortcut {
+            self.typed.pop();
+        } else if !IGNORED.contains(&k) {
+            match self.layer.layout.char_of(k, shift) {
+                Some(c) if self.state == ExpansionState::Active && !shortcut => {
+                    self.typed.push(c);
+                    if self.typed.len() > self.longest {
+                        let start = self.typed.len() - self.longest;
+                        let start = (start..).find(|i| self.typed.is_char_boundary(*i)).unwrap();
+                        self.typed.drain(..start);
+                    }
+                    let found = self
+                        .layer
+                        .abbreviations
+                        .iter()
+                        .find(|(a, _)| self.typed.ends_with(a.as_str()))
+                        .cloned();
+                    if let Some((abbreviation, expansion)) = found {
+                        self.swallowed.insert(k);
+                        self.expand(&abbreviation, &expansion, time);
+                        return;
+                    }
+                }
+                _ => self.typed.clear(),
+            }
+        }
+        self.key_receiver.send_key(key, time);
+    }
+}
+
+impl<T: KeyReceiver + 'static> CustomLayer for Expander<T> {
+    fn name(&self) -> &'static str {
+        self.layer.layer_name
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn set_state(&mut self, state: &str) -> Result<(), String> {
+        let state = match state {
+            "Active" => ExpansionState::Active,
+            "Suspended" => ExpansionState::Suspended,
+            _ => {
+                return Err(format!(
+                    "{} has no state {state}. states: [Active, Suspended]",
+                    self.layer.layer_name
+                ))
+            }
+        };
+        Expander::set_state(self, state);
+        Ok(())
+    }
+
+    fn send_key(&mut self, key: KeyInput, time: SystemTime, _: &Timer) {
+        self.type_key(key, time);
+    }
+
+    fn reset(&mut self) {
+        Expander::set_state(self, ExpansionState::Active);
+        self.typed.clear();
+        self.modifiers.clear();
+        self.swallowed.clear();
+    }
+
+    fn key_receiver(&mut self) -> &mut dyn KeyReceiver {
+        &mut self.key_receiver
+    }
+}
+
+impl ToCustomLayer for ExpansionLayer {
+    fn to_custom_layer(
+        &self,
+        key_receiver: impl KeyReceiver + 'static,
+        _: &LayerContext,
+    ) -> impl CustomLayer {
+        Expander {
+            key_receiver,
+            layer: self.clone(),
+            state: ExpansionState::Active,
+            typed: String::new(),
+            longest: self
+                .abbreviations
+                .iter()
+                .map(|(a, _)| a.len())
+                .max()
+                .unwrap_or(0),
+            modifiers: HashSet::default(),
+            swallowed: HashSet::default(),
+        }
+    }
+}
+
+impl AddLayer for ExpansionLayer {
+    type LayerAdded<A> = CustomLayers<Self, A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        CustomLayers(self, tail)
+    }
+}
@@ -0,0 +1,229 @@
+use crate::hotplug::Watcher;
+use crate::{clock, grab_device, read_device_fd, Event};
+use evdev::Device;
+use rustc_hash::FxHashSet as HashSet;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+/// Sent by the remapper to have the root process start it again.
+const RELOAD: &[u8] = b"reload";
+
+/// Longer than the names of devices, which the kernel cuts at 256 bytes.
+const MESSAGE_SIZE: usize = 512;
+
+/// The two processes of [`fork`].
+pub(crate) enum Fork {
+    /// The process which switches to another user and remaps keys.
+    Remapper(Helper),
+    /// The process which stays root.
+    Root(Root),
+}
+
+/// Fork a process to remap keys, which switches users after opening the devices.
+/// This process stays root to open the devices plugged in later,
+/// since the other user cannot, and to start the remapper again on `reload`.
+/// Call it before starting any thread.
+pub(crate) fn fork() -> io::Result<Fork> {
+    let mut fds = [0; 2];
+    if unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let (root, remapper) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(Fork::Remapper(Helper(remapper))),
+        pid => Ok(Fork::Root(Root { socket: root, pid })),
+    }
+}
+
+/// The remapper's end of the socket to the root process.
+pub(crate) struct Helper(OwnedFd);
+
+impl Helper {
+    /// Send the events of the devices passed by the root process to `tx`.
+    pub fn spawn(&self, tx: Sender<Event>) -> io::Result<()> {
+        let socket = self.0.try_clone()?;
+        thread::spawn(move || {
+            let mut buf = [0; MESSAGE_SIZE];
+            loop {
+                match receive(socket.as_raw_fd(), &mut buf) {
+                    Ok((0, _)) => {
+                        log::warn!("The root process exited. Devices plugged in from now on are not grabbed.");
+                        return;
+                    }
+                    Ok((n, Some(fd))) => {
+                        let name = String::from_utf8_lossy(&buf[1..n]).into_owned();
+                        read_device_fd(fd, name, buf[0] != 0, tx.clone());
+                    }
+                    Ok((_, None)) => {}
+                    Err(e) => {
+                        log::error!("Stopped receiving devices. {e}");
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Have the root process start the remapper again once this process exits.
+    pub fn reload(&self) -> io::Result<()> {
+        send(self.0.as_raw_fd(), RELOAD, None)
+    }
+}
+
+/// The root process's end of the socket to the remapper.
+pub(crate) struct Root {
+    socket: OwnedFd,
+    pid: libc::pid_t,
+}
+
+impl Root {
+    /// Pass the devices plugged in later for which `matches` holds to the remapper,
+    /// grabbed, until it exits. Then exit the same way, or start it again on `reload`.
+    pub fn serve(
+        self,
+        watcher: Option<Watcher>,
+        known: HashSet<PathBuf>,
+        matches: impl Fn(&Device) -> bool + Send + 'static,
+    ) -> ! {
+        if let Some(watcher) = watcher {
+            let socket = self.socket.as_raw_fd();
+            thread::spawn(move || {
+                watcher.watch(known, matches, |mut device| {
+                    let name = device.name().unwrap_or("unknown").to_string();
+                    let grabbed = grab_device(&mut device);
+                    if let Err(e) = clock::use_monotonic(&device) {
+                        log::warn!("Could not set the clock of \"{name}\". {e}");
+                    }
+                    let message = [&[grabbed as u8], name.as_bytes()].concat();
+                    if let Err(e) = send(socket, &message, Some(device.as_raw_fd())) {
+                        log::error!("Could not pass \"{name}\" to the remapper. {e}");
+                    }
+                })
+            });
+        }
+        let mut buf = [0; MESSAGE_SIZE];
+        let reload = loop {
+            match receive(self.socket.as_raw_fd(), &mut buf) {
+                Ok((0, _)) => break false,
+                Ok((n, _)) if &buf[..n] == RELOAD => break true,
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Lost the remapper. {e}");
+                    break false;
+                }
+            }
+        };
+        let status = self.wait();
+        if reload {
+            let mut args = std::env::args_os();
+            let program = args.next().unwrap();
+            let e = std::process::Command::new(program).args(args).exec();
+            eprintln!("Could not reload. {e}");
+            exit(1);
+        }
+        exit(status)
+    }
+
+    /// Wait for the remapper to exit and return its exit status.
+    fn wait(&self) -> i32 {
+        let mut status = 0;
+        while unsafe { libc::waitpid(self.pid, &mut status, 0) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                log::error!("Could not wait for the remapper. {e}");
+                return 1;
+            }
+        }
+        if libc::WIFEXITED(status) {
+            libc::WEXITSTATUS(status)
+        } else {
+            1
+        }
+    }
+}
+
+/// Room for the control message carrying one file descriptor.
+fn control_len() -> usize {
+    unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize }
+}
+
+/// Send `data` as one message, with `fd` if any.
+fn send(socket: RawFd, data: &[u8], fd: Option<RawFd>) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // u64 keeps the control message aligned.
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control_len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            libc::CMSG_DATA(cmsg).cast::<RawFd>().write_unaligned(fd);
+        }
+    }
+    if unsafe { libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a message of [`send`] into `buf`. Returns its length, which is 0
+/// once the other process has exited, and the file descriptor passed with it.
+fn receive(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<OwnedFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control_len() as _;
+    let n = loop {
+        let n = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n != -1 {
+            break n as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    let mut fd = None;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+        {
+            let raw = libc::CMSG_DATA(cmsg).cast::<RawFd>().read_unaligned();
+            fd = Some(OwnedFd::from_raw_fd(raw));
+        }
+    }
+    Ok((n, fd))
+}
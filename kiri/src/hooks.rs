@@ -0,0 +1,192 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+/// What to do when the state of a layer changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateHook {
+    /// Run `program` with `args` followed by the layer name,
+    /// the previous state and the new state.
+    Command { program: PathBuf, args: Vec<String> },
+    /// Keep a file containing a `layer<TAB>state` line for every layer.
+    /// It is replaced, not written in place, when a state changes.
+    StateFile(PathBuf),
+    /// Write a JSON line like
+    /// `{"layer":"SandS","previous":"Normal","state":"Space"}` for each change
+    /// to an existing FIFO, made e.g. with `mkfifo`. Nothing is written while
+    /// no one reads it, and a new reader first gets a line for every layer.
+    JsonLines(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StateChange {
+    pub layer: &'static str,
+    /// `None` when the layer has just been created.
+    pub previous: Option<String>,
+    pub state: String,
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+impl StateChange {
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"layer\":{},\"previous\":{},\"state\":{}}}",
+            json_string(self.layer),
+            self.previous
+                .as_deref()
+                .map_or_else(|| "null".to_string(), json_string),
+            json_string(&self.state)
+        )
+    }
+}
+
+struct JsonLinesWriter {
+    path: PathBuf,
+    file: Option<File>,
+    /// Whether the last error has been logged, so that a missing FIFO
+    /// is not reported on every change.
+    warned: bool,
+}
+
+/// Open the FIFO at `path` for writing. Other files are not opened,
+/// so that they do not grow for as long as the remapper runs.
+fn open_fifo(path: &Path) -> io::Result<File> {
+    // Opening a FIFO without a reader fails with ENXIO instead of blocking.
+    let file = OpenOptions::new()
+        .append(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    if !file.metadata()?.file_type().is_fifo() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a FIFO"));
+    }
+    Ok(file)
+}
+
+impl JsonLinesWriter {
+    /// Write `change`, or every state if the file has not been open.
+    fn write(&mut self, change: &StateChange, states: &[(&'static str, String)]) {
+        let result = match &mut self.file {
+            Some(file) if change.previous.is_some() => writeln!(file, "{}", change.to_json()),
+            Some(_) => Ok(()),
+            None => open_fifo(&self.path).and_then(|mut file| {
+                for (layer, state) in states {
+                    let line = StateChange {
+                        layer,
+                        previous: None,
+                        state: state.clone(),
+                    }
+                    .to_json();
+                    writeln!(file, "{line}")?;
+                }
+                self.file = Some(file);
+                Ok(())
+            }),
+        };
+        match result {
+            Ok(()) => self.warned = false,
+            Err(e) => {
+                if e.raw_os_error() != Some(libc::ENXIO) && !self.warned {
+                    log::warn!("{}: {e}", self.path.display());
+                    self.warned = true;
+                }
+                self.file = None;
+            }
+        }
+    }
+}
+
+fn write_state_file(path: &Path, states: &[(&'static str, String)]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for (layer, state) in states {
+        writeln!(file, "{layer}\t{state}")?;
+    }
+    fs::rename(tmp, path)
+}
+
+fn run_command(program: &Path, args: &[String], change: &StateChange) {
+    let child = Command::new(program)
+        .args(args)
+        .arg(change.layer)
+        .arg(change.previous.as_deref().unwrap_or_default())
+        .arg(&change.state)
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => log::warn!("could not run {}: {e}", program.display()),
+    }
+}
+
+/// Start a thread running `hooks` for every state change sent to the returned sender.
+pub(crate) fn spawn(hooks: Vec<StateHook>) -> Sender<StateChange> {
+    let (tx, rx) = channel::<StateChange>();
+    thread::spawn(move || {
+        let mut states: Vec<(&'static str, String)> = Vec::new();
+        let mut json_writers: Vec<JsonLinesWriter> = hooks
+            .iter()
+            .filter_map(|h| match h {
+                StateHook::JsonLines(path) => Some(JsonLinesWriter {
+                    path: path.clone(),
+                    file: None,
+                    warned: false,
+                }),
+                _ => None,
+            })
+            .collect();
+        for change in rx {
+            // Stacks of several devices report the same layers.
+            let changed = match states.iter_mut().find(|(l, _)| *l == change.layer) {
+                Some((_, s)) if *s == change.state => false,
+                Some((_, s)) => {
+                    *s = change.state.clone();
+                    true
+                }
+                None => {
+                    states.push((change.layer, change.state.clone()));
+                    true
+                }
+            };
+            for hook in &hooks {
+                match hook {
+                    StateHook::Command { program, args } if change.previous.is_some() => {
+                        run_command(program, args, &change)
+                    }
+                    StateHook::StateFile(path) if changed => {
+                        if let Err(e) = write_state_file(path, &states) {
+                            log::warn!("{}: {e}", path.display());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            for w in &mut json_writers {
+                w.write(&change, &states);
+            }
+        }
+    });
+    tx
+}
@@ -0,0 +1,124 @@
+use crate::{grab_device, read_device, Event};
+use evdev::Device;
+use rustc_hash::FxHashSet as HashSet;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+const INPUT_DIR: &str = "/dev/input";
+
+/// Watches `/dev/input` for devices plugged in after startup.
+pub(crate) struct Watcher {
+    fd: RawFd,
+}
+
+impl Watcher {
+    /// Start watching. Devices created from now on are reported by [`Self::spawn`],
+    /// so enumerate the existing ones after this.
+    pub fn new() -> io::Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let dir = c"/dev/input";
+        // udev makes new nodes readable after creating them, which is an IN_ATTRIB.
+        let mask = libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_DELETE;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } == -1 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(Watcher { fd })
+    }
+
+    /// Grab the new devices for which `matches` holds and send their events to `tx`.
+    /// `known` are the devices opened before, which are left alone.
+    pub fn spawn(
+        self,
+        known: HashSet<PathBuf>,
+        matches: impl Fn(&Device) -> bool + Send + 'static,
+        tx: Sender<Event>,
+    ) {
+        thread::spawn(move || {
+            self.watch(known, matches, |mut device| {
+                let grabbed = grab_device(&mut device);
+                read_device(device, grabbed, tx.clone());
+            })
+        });
+    }
+
+    /// Pass the new devices for which `matches` holds to `found` until watching fails.
+    /// `known` are the devices opened before, which are left alone.
+    pub fn watch(
+        self,
+        mut known: HashSet<PathBuf>,
+        matches: impl Fn(&Device) -> bool,
+        mut found: impl FnMut(Device),
+    ) {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("Stopped watching {INPUT_DIR}. {e}");
+                return;
+            }
+            for (mask, name) in events(&buf[..n as usize]) {
+                if !name.as_bytes().starts_with(b"event") {
+                    continue;
+                }
+                let path = Path::new(INPUT_DIR).join(name);
+                if mask & libc::IN_DELETE != 0 {
+                    known.remove(&path);
+                } else if !known.contains(&path) {
+                    open(&path, &mut known, &matches, &mut found);
+                }
+            }
+        }
+    }
+}
+
+/// The masks and file names of the inotify events in `buf`.
+fn events(mut buf: &[u8]) -> Vec<(u32, &OsStr)> {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut events = Vec::new();
+    while buf.len() >= header {
+        let event = unsafe { buf.as_ptr().cast::<libc::inotify_event>().read_unaligned() };
+        let end = header + event.len as usize;
+        let name = buf[header..end]
+            .split(|b| *b == 0)
+            .next()
+            .unwrap_or_default();
+        events.push((event.mask, OsStr::from_bytes(name)));
+        buf = &buf[end..];
+    }
+    events
+}
+
+fn open(
+    path: &Path,
+    known: &mut HashSet<PathBuf>,
+    matches: &impl Fn(&Device) -> bool,
+    found: &mut impl FnMut(Device),
+) {
+    let device = match Device::open(path) {
+        Ok(device) => device,
+        // Not readable yet.
+        Err(e) => {
+            log::debug!("Could not open {}. {e}", path.display());
+            return;
+        }
+    };
+    known.insert(path.to_path_buf());
+    if matches(&device) {
+        log::info!("\"{}\" plugged in", device.name().unwrap_or("unknown"));
+        found(device);
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets. micro sec.
+const BUCKETS: [u128; 10] = [
+    250,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    20_000,
+    50_000,
+    100_000,
+    u128::MAX,
+];
+
+/// Times from input events to the keys they caused being written to the virtual devices.
+/// Keys held back to wait for a pair, for more taps or for a timeout include the wait,
+/// since they carry the time of the input which started it.
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+    /// Number of keys in each bucket.
+    histogram: [usize; BUCKETS.len()],
+    total: Duration,
+    max: Duration,
+}
+
+impl Latencies {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros();
+        let bucket = BUCKETS.iter().position(|b| us < *b).unwrap();
+        self.histogram[bucket] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// The histogram as lines for the control socket.
+    pub fn report(&self) -> Vec<String> {
+        let count = self.histogram.iter().sum::<usize>();
+        if count == 0 {
+            return vec!["no keys written".to_string()];
+        }
+        let mut lines = vec![format!(
+            "{count} keys, mean {} us, max {} us",
+            self.total.as_micros() / count as u128,
+            self.max.as_micros()
+        )];
+        let max = self.histogram.iter().max().copied().unwrap_or(0).max(1);
+        let mut lower = 0;
+        for (upper, n) in BUCKETS.iter().zip(self.histogram) {
+            let range = if *upper == u128::MAX {
+                format!("{lower:>6}-       us")
+            } else {
+                format!("{lower:>6}-{upper:>6} us")
+            };
+            lines.push(format!("{range} | {:<40} {n}", "#".repeat(n * 40 / max)));
+            lower = *upper;
+        }
+        lines
+    }
+}
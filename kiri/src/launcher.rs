@@ -0,0 +1,167 @@
+use crate::custom_layer::{CustomLayer, CustomLayers, Timer, ToCustomLayer};
+use crate::privileges::{is_root, User};
+use crate::read_keys::{AddLayer, KeyInput, KeyInputKind, KeyReceiver, LayerContext};
+use evdev::Key;
+use rustc_hash::FxHashSet as HashSet;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::SystemTime;
+
+/// A layer running a command instead of sending a key.
+/// Put it after the layers which output the keys, e.g. `PROG1` for caps lock + T.
+///
+/// Commands run without a shell, as `user` or the logged-in desktop user,
+/// in the user's home directory with only the variables of the session set.
+/// Without root, e.g. after [`KeyConfig::run_as`](crate::KeyConfig::run_as),
+/// they run as the user of the remapper.
+#[derive(Debug, Clone)]
+pub struct CommandLayer {
+    pub layer_name: &'static str,
+    /// A key, the program run when it is pressed, and the arguments.
+    pub commands: Vec<(Key, PathBuf, Vec<String>)>,
+    /// The user running the commands. If `None`, `SUDO_USER`, or else
+    /// the owner of the only session of a person in `/run/user`.
+    /// Ignored without root.
+    pub user: Option<String>,
+    /// Variables set besides `HOME`, `USER`, `LOGNAME`, `PATH`,
+    /// `XDG_RUNTIME_DIR` and `DBUS_SESSION_BUS_ADDRESS`,
+    /// e.g. `WAYLAND_DISPLAY` or `DISPLAY`.
+    pub env: Vec<(String, String)>,
+}
+
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+fn run(program: &Path, args: &[String], user: Option<&str>, env: &[(String, String)]) {
+    // Without root, commands can only run as the user of this process.
+    let root = is_root();
+    let user = match if root {
+        User::find(user)
+    } else {
+        User::current()
+    } {
+        Ok(user) => user,
+        Err(e) => {
+            log::error!("could not run {}: {e}", program.display());
+            return;
+        }
+    };
+    let runtime_dir = format!("/run/user/{}", user.uid);
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env_clear()
+        .env("HOME", &user.home)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("PATH", PATH)
+        .env(
+            "DBUS_SESSION_BUS_ADDRESS",
+            format!("unix:path={runtime_dir}/bus"),
+        )
+        .env("XDG_RUNTIME_DIR", runtime_dir)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .current_dir(&user.home)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if root {
+        // Supplementary groups have to be set before the uid,
+        // which `CommandExt::uid` does not allow.
+        let switched = user.clone();
+        unsafe {
+            command.pre_exec(move || switched.switch());
+        }
+    }
+    match command.spawn() {
+        Ok(mut child) => {
+            log::info!("run {} as {}", program.display(), user.name);
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => log::error!("could not run {}: {e}", program.display()),
+    }
+}
+
+struct Launcher<T> {
+    key_receiver: T,
+    layer: CommandLayer,
+    /// Keys which ran a command, whose repeats and releases are dropped.
+    swallowed: HashSet<Key>,
+    run_commands: bool,
+}
+
+impl<T: KeyReceiver + 'static> CustomLayer for Launcher<T> {
+    fn name(&self) -> &'static str {
+        self.layer.layer_name
+    }
+
+    fn state(&self) -> String {
+        "Active".to_string()
+    }
+
+    fn set_state(&mut self, state: &str) -> Result<(), String> {
+        match state {
+            "Active" => Ok(()),
+            _ => Err(format!(
+                "{} has no state {state}. states: [Active]",
+                self.layer.layer_name
+            )),
+        }
+    }
+
+    fn send_key(&mut self, key: KeyInput, time: SystemTime, _: &Timer) {
+        let KeyInput(k, kind) = key;
+        if kind == KeyInputKind::Release {
+            if !self.swallowed.remove(&k) {
+                self.key_receiver.send_key(key, time);
+            }
+            return;
+        }
+        if self.swallowed.contains(&k) {
+            return;
+        }
+        match self.layer.commands.iter().find(|(c, _, _)| *c == k) {
+            Some((_, program, args)) => {
+                self.swallowed.insert(k);
+                if self.run_commands {
+                    run(program, args, self.layer.user.as_deref(), &self.layer.env);
+                } else {
+                    log::info!("would run {}", program.display());
+                }
+            }
+            None => self.key_receiver.send_key(key, time),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.swallowed.clear();
+    }
+
+    fn key_receiver(&mut self) -> &mut dyn KeyReceiver {
+        &mut self.key_receiver
+    }
+}
+
+impl ToCustomLayer for CommandLayer {
+    fn to_custom_layer(
+        &self,
+        key_receiver: impl KeyReceiver + 'static,
+        context: &LayerContext,
+    ) -> impl CustomLayer {
+        Launcher {
+            key_receiver,
+            layer: self.clone(),
+            swallowed: HashSet::default(),
+            run_commands: context.run_commands,
+        }
+    }
+}
+
+impl AddLayer for CommandLayer {
+    type LayerAdded<A> = CustomLayers<Self, A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        CustomLayers(self, tail)
+    }
+}
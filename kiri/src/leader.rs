@@ -0,0 +1,179 @@
+use crate::custom_layer::{CustomLayer, CustomLayers, Timer, ToCustomLayer};
+use crate::read_keys::{AddLayer, KeyInput, KeyInputKind, KeyReceiver, LayerContext};
+use evdev::Key;
+use rustc_hash::FxHashSet as HashSet;
+use std::time::{Duration, SystemTime};
+
+/// A layer where tapping `leader` and then typing a sequence of keys
+/// sends the output of the sequence.
+/// If the typed keys match no sequence, or the next key is not typed within
+/// `timeout`, the leader and the typed keys are sent as they are.
+///
+/// The state is `Idle`, or `Leader` followed by the keys typed so far.
+#[derive(Debug, Clone)]
+pub struct LeaderLayer {
+    pub layer_name: &'static str,
+    pub leader: Key,
+    /// Keys typed after the leader and the output sent when they are typed.
+    pub sequences: Vec<(Vec<Key>, Vec<KeyInput>)>,
+    /// Milli sec. allowed between the keys of a sequence.
+    pub timeout: u32,
+}
+
+struct Leader<T> {
+    key_receiver: T,
+    layer: LeaderLayer,
+    /// Keys typed after the leader, or `None` when the leader has not been pressed.
+    typed: Option<Vec<Key>>,
+    /// The last key press, which the timer is set for.
+    last: Option<(KeyInput, SystemTime)>,
+    /// Keys whose presses were not sent, so their releases are not sent either.
+    swallowed: HashSet<Key>,
+}
+
+impl<T: KeyReceiver> Leader<T> {
+    fn tap(&mut self, key: Key, time: SystemTime) {
+        self.key_receiver.send_key(KeyInput::press(key), time);
+        self.key_receiver.send_key(KeyInput::release(key), time);
+    }
+
+    /// Send the leader and the typed keys as they are.
+    fn fall_back(&mut self, time: SystemTime) {
+        let typed = self.typed.take().unwrap_or_default();
+        log::debug!("[{}] no sequence {:?}", self.layer.layer_name, typed);
+        self.tap(self.layer.leader, time);
+        for k in typed {
+            self.tap(k, time);
+        }
+        self.last = None;
+    }
+}
+
+impl<T: KeyReceiver + 'static> CustomLayer for Leader<T> {
+    fn name(&self) -> &'static str {
+        self.layer.layer_name
+    }
+
+    fn state(&self) -> String {
+        match &self.typed {
+            None => "Idle".to_string(),
+            Some(typed) => ["Leader".to_string()]
+                .into_iter()
+                .chain(typed.iter().map(|k| {
+                    let name = format!("{k:?}");
+                    name.strip_prefix("KEY_").unwrap_or(&name).to_string()
+                }))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    fn set_state(&mut self, state: &str) -> Result<(), String> {
+        match state {
+            "Idle" => {
+                self.typed = None;
+                self.last = None;
+                Ok(())
+            }
+            "Leader" => {
+                self.typed = Some(Vec::new());
+                self.last = None;
+                Ok(())
+            }
+            _ => Err(format!(
+                "{} has no state {state}. states: [Idle, Leader]",
+                self.layer.layer_name
+            )),
+        }
+    }
+
+    fn send_key(&mut self, key: KeyInput, time: SystemTime, timer: &Timer) {
+        let KeyInput(k, kind) = key;
+        if kind == KeyInputKind::Release {
+            if !self.swallowed.remove(&k) {
+                self.key_receiver.send_key(key, time);
+            }
+            return;
+        }
+        if self.swallowed.contains(&k) {
+            // Repeats of a key which is a part of a sequence.
+            return;
+        }
+        let Some(typed) = &mut self.typed else {
+            if k == self.layer.leader {
+                self.swallowed.insert(k);
+                self.typed = Some(Vec::new());
+                self.last = Some((key, time));
+                timer.set(key, time, Duration::from_millis(self.layer.timeout.into()));
+            } else {
+                self.key_receiver.send_key(key, time);
+            }
+            return;
+        };
+        typed.push(k);
+        let typed = typed.clone();
+        if let Some((_, output)) = self.layer.sequences.iter().find(|(s, _)| *s == typed) {
+            log::debug!("[{}] sequence {:?}", self.layer.layer_name, typed);
+            self.swallowed.insert(k);
+            for o in output.clone() {
+                self.key_receiver.send_key(o, time);
+            }
+            self.typed = None;
+            self.last = None;
+        } else if self
+            .layer
+            .sequences
+            .iter()
+            .any(|(s, _)| s.starts_with(&typed))
+        {
+            self.swallowed.insert(k);
+            self.last = Some((key, time));
+            timer.set(key, time, Duration::from_millis(self.layer.timeout.into()));
+        } else {
+            self.typed.as_mut().unwrap().pop();
+            self.fall_back(time);
+            self.key_receiver.send_key(key, time);
+        }
+    }
+
+    fn timeout(&mut self, key: KeyInput, time: SystemTime, _: &Timer) {
+        if self.last == Some((key, time)) {
+            // Stamped with the last key typed, so that the latency includes the wait.
+            self.fall_back(time);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.typed = None;
+        self.last = None;
+        self.swallowed.clear();
+    }
+
+    fn key_receiver(&mut self) -> &mut dyn KeyReceiver {
+        &mut self.key_receiver
+    }
+}
+
+impl ToCustomLayer for LeaderLayer {
+    fn to_custom_layer(
+        &self,
+        key_receiver: impl KeyReceiver + 'static,
+        _: &LayerContext,
+    ) -> impl CustomLayer {
+        Leader {
+            key_receiver,
+            layer: self.clone(),
+            typed: None,
+            last: None,
+            swallowed: HashSet::default(),
+        }
+    }
+}
+
+impl AddLayer for LeaderLayer {
+    type LayerAdded<A> = CustomLayers<Self, A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        CustomLayers(self, tail)
+    }
+}
@@ -0,0 +1,803 @@
+mod chatter;
+mod clock;
+mod config_file;
+mod control;
+mod custom_layer;
+mod debounce;
+mod expand;
+mod helper;
+mod hooks;
+mod hotplug;
+mod latency;
+mod launcher;
+mod leader;
+mod macros;
+mod one_shot;
+mod pattern;
+mod pointer;
+mod privileges;
+mod read_keys;
+mod simulate;
+mod write_keys;
+
+pub use crate::chatter::{chattering_report, diagnose_chattering};
+use crate::clock::Clock;
+pub use crate::config_file::{load_layers, parse_layers, LayersFile, StateName};
+pub use crate::control::send_command;
+use crate::control::{Command, Reply, Request};
+pub use crate::debounce::{DebounceAlgorithm, DebounceConfig};
+pub use crate::expand::{ExpansionLayer, KeyboardLayout};
+use crate::helper::{Fork, Helper};
+pub use crate::hooks::StateHook;
+pub use crate::launcher::CommandLayer;
+pub use crate::leader::LeaderLayer;
+use crate::macros::Macros;
+pub use crate::macros::{MacroConfig, MacroTiming};
+pub use crate::one_shot::OneShotLayer;
+pub use crate::pattern::{KeyPattern, PatternInput, PatternOutput};
+use crate::pointer::{is_motion, wheel_clicks, POINTER_NAME};
+pub use crate::pointer::{
+    POINTER_DOWN, POINTER_LEFT, POINTER_RIGHT, POINTER_UP, SCROLL_DOWN, SCROLL_LEFT, SCROLL_RIGHT,
+    SCROLL_UP, WHEEL_DOWN, WHEEL_LEFT, WHEEL_RIGHT, WHEEL_UP,
+};
+use crate::privileges::{prepare_dir, User};
+pub use crate::read_keys::{
+    AddLayer, KeyConfig, KeyInput, PairPatternEntry, PairRemapEntry, RemapLayer,
+    SinglePatternEntry, SingleRemapEntry, TapDanceEntry, DEFAULT_EMERGENCY_STOP_CHORD,
+};
+use crate::read_keys::{KeyReceiver, KeyRecorder, LayerContext, LayerControl, ToKeyRecorder};
+pub use crate::simulate::{load_trace, parse_trace, record_trace, Simulator};
+use crate::write_keys::{KeyWriter, KeyWriterHandle, SharedKeyWriter, KEYBOARD_NAME};
+pub use evdev::Key;
+use evdev::{Device, InputEvent, InputEventKind, RelativeAxisType};
+pub use evdev_keys;
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::SystemTime;
+
+fn is_keyboard(device: &Device) -> bool {
+    device.supported_keys().is_some_and(|supported_keys| {
+        supported_keys.contains(Key::KEY_A)
+            && supported_keys.contains(Key::KEY_Z)
+            && supported_keys.contains(Key::KEY_SPACE)
+    })
+}
+
+/// Mice which are not also keyboards.
+fn is_mouse(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::BTN_LEFT))
+        && device
+            .supported_relative_axes()
+            .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X))
+        && !is_keyboard(device)
+}
+
+/// The virtual keyboard and pointer, which must not be grabbed.
+fn is_virtual(device: &Device) -> bool {
+    device
+        .name()
+        .is_some_and(|name| name == KEYBOARD_NAME || name == POINTER_NAME)
+}
+
+fn get_keyboard_devices() -> impl Iterator<Item = (PathBuf, Device)> {
+    evdev::enumerate().filter(|(_, device)| is_keyboard(device) && !is_virtual(device))
+}
+
+fn get_mouse_devices() -> impl Iterator<Item = (PathBuf, Device)> {
+    evdev::enumerate().filter(|(_, device)| is_mouse(device) && !is_virtual(device))
+}
+
+/// `EVIOCGRAB` of linux/input.h.
+const EVIOCGRAB: libc::c_ulong = 0x4004_4590;
+
+/// Release the grab of devices whose threads are blocked reading them.
+fn ungrab_devices<'a>(fds: impl IntoIterator<Item = &'a RawFd>) {
+    for fd in fds {
+        if unsafe { libc::ioctl(*fd, EVIOCGRAB, 0 as libc::c_int) } == -1 {
+            log::error!(
+                "Could not ungrab a device. {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Identifies a device from when it is opened until it is removed.
+pub(crate) type DeviceId = usize;
+
+static NEXT_DEVICE_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) enum Event {
+    Input(DeviceId, InputEvent),
+    /// A device has been opened and its events follow.
+    /// `grabbed` is its file descriptor if it has been grabbed.
+    Added {
+        id: DeviceId,
+        name: String,
+        grabbed: Option<RawFd>,
+    },
+    /// A device has been removed and sends no more events.
+    Removed(DeviceId),
+    Request(Request),
+    /// The emergency stop chord has been pressed, as read before debouncing.
+    EmergencyStop,
+}
+
+/// Send the events of `device` to `tx` until it is removed.
+fn read_device(mut device: Device, grabbed: bool, tx: Sender<Event>) {
+    let id = NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed);
+    let name = device.name().unwrap_or("unknown").to_string();
+    if let Err(e) = clock::use_monotonic(&device) {
+        log::warn!("Could not set the clock of \"{name}\". {e}");
+    }
+    let _ = tx.send(Event::Added {
+        id,
+        name: name.clone(),
+        grabbed: grabbed.then(|| device.as_raw_fd()),
+    });
+    thread::spawn(move || loop {
+        match device.fetch_events() {
+            Ok(events) => {
+                for input_event in events {
+                    if tx.send(Event::Input(id, input_event)).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                log::info!("\"{name}\" removed. {e}");
+                let _ = tx.send(Event::Removed(id));
+                return;
+            }
+        }
+    });
+}
+
+/// Send the events read from `fd`, a device opened by the root process
+/// after switching users, to `tx` until it is removed.
+fn read_device_fd(fd: OwnedFd, name: String, grabbed: bool, tx: Sender<Event>) {
+    let id = NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed);
+    let _ = tx.send(Event::Added {
+        id,
+        name: name.clone(),
+        grabbed: grabbed.then(|| fd.as_raw_fd()),
+    });
+    let mut file = File::from(fd);
+    thread::spawn(move || {
+        const SIZE: usize = mem::size_of::<libc::input_event>();
+        let mut buf = [0u8; 64 * SIZE];
+        loop {
+            let n = match file.read(&mut buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                // Removed devices fail with ENODEV rather than reaching the end.
+                Ok(0) | Err(_) => {
+                    log::info!("\"{name}\" removed.");
+                    let _ = tx.send(Event::Removed(id));
+                    return;
+                }
+                Ok(n) => n,
+            };
+            for chunk in buf[..n].chunks_exact(SIZE) {
+                let raw = unsafe { chunk.as_ptr().cast::<libc::input_event>().read_unaligned() };
+                if tx.send(Event::Input(id, InputEvent::from(raw))).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Send the events of `devices` to `tx` without grabbing them.
+fn read_devices(devices: impl Iterator<Item = Device>, tx: Sender<Event>) {
+    for d in devices {
+        read_device(d, false, tx.clone());
+    }
+}
+
+/// Grab `device`, or log why it could not be grabbed.
+fn grab_device(device: &mut Device) -> bool {
+    if let Err(e) = device.grab() {
+        match e.raw_os_error() {
+            Some(16) => {
+                log::error!(
+                    "Could not grab \"{}\". {e}. \
+                    Maybe there is another key remapper running.",
+                    device.name().unwrap_or("unknown"),
+                )
+            }
+            _ => {
+                log::error!(
+                    "Could not grab \"{}\". {e}.",
+                    device.name().unwrap_or("unknown"),
+                );
+            }
+        }
+        false
+    } else {
+        log::info!(
+            "Successfully grabed \"{}\".",
+            device.name().unwrap_or("unknown")
+        );
+        true
+    }
+}
+
+fn make_read_channel(devices: impl Iterator<Item = Device>, tx: Sender<Event>) -> Vec<RawFd> {
+    let mut grabbed = Vec::new();
+    for mut d in devices {
+        let g = grab_device(&mut d);
+        if g {
+            grabbed.push(d.as_raw_fd());
+        }
+        read_device(d, g, tx.clone());
+    }
+    grabbed
+}
+
+/// Give the control socket and the directories of `files` to `name`, and switch to it.
+fn switch_user<'a>(
+    name: &str,
+    socket: Option<&Path>,
+    files: impl Iterator<Item = &'a Path>,
+) -> std::io::Result<()> {
+    let user = User::by_name(name)?;
+    if let Some(socket) = socket {
+        std::os::unix::fs::chown(socket, Some(user.uid), Some(user.gid))?;
+    }
+    for file in files {
+        prepare_dir(file, &user)?;
+    }
+    user.switch()?;
+    log::info!("Running as {}", user.name);
+    Ok(())
+}
+
+/// Whether pressing `key` completes `chord` while `pressed_keys` are held.
+fn completes_chord(chord: &[Key], key: Key, pressed_keys: &HashSet<Key>) -> bool {
+    chord.contains(&key) && chord.iter().all(|k| pressed_keys.contains(k))
+}
+
+/// Check that the emergency stop chord can be typed on `keyboards` and
+/// cannot be confused with other chords, i.e. neither contains the other.
+fn validate_emergency_stop_chord(
+    chord: &[Key],
+    other_chords: &[(&str, &[Key])],
+    keyboards: &[Device],
+) -> Result<(), String> {
+    if chord.len() < 2 {
+        return Err("emergency stop chord must consist of at least two keys".to_string());
+    }
+    if chord.iter().collect::<HashSet<_>>().len() != chord.len() {
+        return Err(format!("emergency stop chord {chord:?} has duplicate keys"));
+    }
+    let keys = chord.iter().collect::<HashSet<_>>();
+    for (name, other) in other_chords.iter().filter(|(_, o)| !o.is_empty()) {
+        let other_keys = other.iter().collect::<HashSet<_>>();
+        if keys == other_keys {
+            return Err(format!(
+                "emergency stop chord {chord:?} is the same as the {name} chord"
+            ));
+        }
+        if keys.is_superset(&other_keys) || keys.is_subset(&other_keys) {
+            return Err(format!(
+                "emergency stop chord {chord:?} overlaps the {name} chord {other:?}"
+            ));
+        }
+    }
+    let missing = chord
+        .iter()
+        .filter(|k| {
+            !keyboards
+                .iter()
+                .any(|d| d.supported_keys().is_some_and(|s| s.contains(**k)))
+        })
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!(
+            "emergency stop chord {chord:?} cannot be typed: \
+            no keyboard has {missing:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// An opened device.
+struct DeviceState {
+    name: String,
+    stack: StackId,
+    /// File descriptor of the device if it has been grabbed.
+    grabbed: Option<RawFd>,
+    /// Keys held on the device.
+    pressed_keys: HashSet<Key>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum StackId {
+    /// The stack shared by the devices of a group of [`KeyConfig::merge_devices`].
+    Group(usize),
+    /// The stack of a device in no group.
+    Device(DeviceId),
+}
+
+/// A layer stack and the devices whose keys go through it.
+struct Stack {
+    name: String,
+    key_recorder: KeyRecorder,
+    devices: usize,
+    /// Time of the last key sent to the layers. Keys from several devices are
+    /// sent in the order they arrive, which may differ from the order of their
+    /// timestamps. The layers expect non-decreasing times, so a key stamped
+    /// before the last one is sent with the time of the last one.
+    last_time: SystemTime,
+}
+
+/// State of the loop which dispatches input events.
+struct Dispatcher<'a> {
+    devices: HashMap<DeviceId, DeviceState>,
+    stacks: HashMap<StackId, Stack>,
+    /// Devices whose names contain one of the names of a group share a stack.
+    device_groups: &'a [Vec<String>],
+    /// Builds a fresh layer stack.
+    new_stack: Box<dyn Fn() -> KeyRecorder + 'a>,
+    writer: SharedKeyWriter,
+    /// Receives keys while paused.
+    passthrough: KeyWriterHandle,
+    paused: bool,
+    macros: Option<Macros>,
+    /// The process which stayed root after switching to another user.
+    helper: Option<Helper>,
+}
+
+impl Dispatcher<'_> {
+    fn add_device(&mut self, id: DeviceId, name: String, grabbed: Option<RawFd>) {
+        let group = self
+            .device_groups
+            .iter()
+            .position(|g| g.iter().any(|n| name.contains(n.as_str())));
+        let stack_id = group.map_or(StackId::Device(id), StackId::Group);
+        let stack = self.stacks.entry(stack_id).or_insert_with(|| {
+            let stack_name = match group {
+                Some(g) => self.device_groups[g].join("+"),
+                None => name.clone(),
+            };
+            log::info!("New layer stack for \"{stack_name}\"");
+            Stack {
+                name: stack_name,
+                key_recorder: (self.new_stack)(),
+                devices: 0,
+                last_time: SystemTime::UNIX_EPOCH,
+            }
+        });
+        stack.devices += 1;
+        self.devices.insert(
+            id,
+            DeviceState {
+                name,
+                stack: stack_id,
+                grabbed,
+                pressed_keys: HashSet::default(),
+            },
+        );
+    }
+
+    /// Release the keys held on a removed device, and end its layer stack
+    /// unless other devices share it.
+    fn remove_device(&mut self, id: DeviceId, pressed_keys: &mut HashSet<Key>) {
+        let Some(device) = self.devices.remove(&id) else {
+            return;
+        };
+        for k in &device.pressed_keys {
+            pressed_keys.remove(k);
+        }
+        let Some(stack) = self.stacks.get_mut(&device.stack) else {
+            return;
+        };
+        stack.devices -= 1;
+        if stack.devices == 0 {
+            log::info!("Layer stack for \"{}\" removed", stack.name);
+            stack.key_recorder.stop();
+            self.stacks.remove(&device.stack);
+        } else if !self.paused {
+            log::info!("\"{}\" left \"{}\"", device.name, stack.name);
+            let time = clock::now().max(stack.last_time);
+            for k in device.pressed_keys {
+                stack.key_recorder.send_key(KeyInput::release(k), time);
+            }
+        }
+    }
+
+    fn reset_layers(&mut self) {
+        for stack in self.stacks.values_mut() {
+            stack.key_recorder.reset();
+        }
+    }
+
+    fn ungrab(&self) {
+        ungrab_devices(self.devices.values().filter_map(|d| d.grabbed.as_ref()));
+    }
+
+    fn emergency_stop(&self) -> ! {
+        log::info!("Emergency stop");
+        // Keys held by the virtual keyboard are released by
+        // the kernel when the process exits.
+        self.ungrab();
+        exit(0)
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        if paused {
+            log::info!("Paused");
+            self.reset_layers();
+        } else {
+            log::info!("Resumed");
+            self.passthrough.release_all();
+        }
+        self.paused = paused;
+    }
+
+    fn status(&self) -> &'static str {
+        if self.paused {
+            "paused"
+        } else {
+            "running"
+        }
+    }
+
+    /// Send `message` to the layers of every device.
+    /// Succeeds if it succeeds for any of them.
+    fn control_layers(
+        &mut self,
+        message: impl Fn(Sender<Result<(), String>>) -> LayerControl,
+    ) -> Result<(), String> {
+        let mut result = Err("no device".to_string());
+        for stack in self.stacks.values_mut() {
+            let (tx, rx) = channel();
+            stack.key_recorder.control(message(tx));
+            let r = rx.recv().unwrap();
+            if result.is_err() {
+                result = r;
+            }
+        }
+        result
+    }
+
+    fn handle_request(&mut self, Request { command, reply }: Request) {
+        let r = match command {
+            Command::Pause => {
+                self.set_paused(true);
+                Reply::line(self.status())
+            }
+            Command::Resume => {
+                self.set_paused(false);
+                Reply::line(self.status())
+            }
+            Command::TogglePause => {
+                self.set_paused(!self.paused);
+                Reply::line(self.status())
+            }
+            Command::Status => Reply::line(self.status()),
+            Command::Layers => {
+                let mut lines = Vec::new();
+                for stack in self.stacks.values_mut() {
+                    let (tx, rx) = channel();
+                    stack
+                        .key_recorder
+                        .control(LayerControl::Query(Vec::new(), tx));
+                    lines.extend(rx.recv().unwrap().into_iter().map(|s| {
+                        format!(
+                            "{}\t{}\t{}\t{}",
+                            s.name,
+                            s.state,
+                            if s.enabled { "enabled" } else { "disabled" },
+                            stack.name
+                        )
+                    }));
+                }
+                Reply::Lines(lines)
+            }
+            Command::SetState { layer, state } => {
+                let r = self.control_layers(|reply| LayerControl::SetState {
+                    layer: layer.clone(),
+                    state: state.clone(),
+                    reply,
+                });
+                Reply::line(r.map_or_else(|e| format!("error: {e}"), |()| "ok".to_string()))
+            }
+            Command::SetEnabled { layer, enabled } => {
+                let r = self.control_layers(|reply| LayerControl::SetEnabled {
+                    layer: layer.clone(),
+                    enabled,
+                    reply,
+                });
+                Reply::line(r.map_or_else(|e| format!("error: {e}"), |()| "ok".to_string()))
+            }
+            Command::Reload => {
+                let (tx, rx) = channel();
+                let _ = reply.send(Reply::Last(vec!["reloading".to_string()], tx));
+                let _ = rx.recv_timeout(control::RELOAD_REPLY_TIMEOUT);
+                self.reload();
+            }
+            Command::Events => Reply::Stream(self.writer.subscribe()),
+            Command::Latency { reset } => Reply::Lines(self.writer.latencies(reset)),
+        };
+        let _ = reply.send(r);
+    }
+
+    /// Replace this process with a new one started with the same arguments.
+    fn reload(&mut self) -> ! {
+        log::info!("Reloading");
+        self.ungrab();
+        if let Some(helper) = &self.helper {
+            // The root process starts the remapper again once this one exits.
+            if let Err(e) = helper.reload() {
+                eprintln!("Could not reload. {e}");
+                exit(1);
+            }
+            exit(0);
+        }
+        let mut args = std::env::args_os();
+        let program = args.next().unwrap();
+        let e = std::process::Command::new(program).args(args).exec();
+        eprintln!("Could not reload. {e}");
+        exit(1)
+    }
+
+    fn send_key(&mut self, id: DeviceId, key: KeyInput, time: SystemTime) {
+        if self.paused {
+            self.passthrough.fire_key_input(key, Some(time));
+        } else if let Some(stack) = self
+            .devices
+            .get(&id)
+            .and_then(|d| self.stacks.get_mut(&d.stack))
+        {
+            stack.last_time = time.max(stack.last_time);
+            stack.key_recorder.send_key(key, stack.last_time);
+        }
+    }
+}
+
+pub trait KeyConfigRun {
+    fn run(self);
+    /// A [`Simulator`] running the layers of this config.
+    /// Keys are debounced as configured, but the devices, chords and macros are not simulated.
+    fn simulator(self) -> Simulator;
+}
+
+impl<T: ToKeyRecorder> KeyConfigRun for KeyConfig<T> {
+    fn simulator(self) -> Simulator {
+        Simulator::new(&self.layers, self.debounce)
+    }
+
+    fn run(self) {
+        // Started before enumerating so that no device plugged in meanwhile is missed.
+        let watcher = hotplug::Watcher::new()
+            .map_err(|e| log::error!("Hotplug is disabled. {e}"))
+            .ok();
+        let (keyboard_paths, keyboards): (Vec<_>, Vec<_>) = get_keyboard_devices().unzip();
+        if keyboards.is_empty() {
+            eprintln!("Keyboard not found");
+            exit(1);
+        }
+        let (mouse_paths, mice): (Vec<_>, Vec<_>) = if self.grab_mice {
+            get_mouse_devices().unzip()
+        } else {
+            Default::default()
+        };
+        let known: HashSet<_> = keyboard_paths.into_iter().chain(mouse_paths).collect();
+        let grab_mice = self.grab_mice;
+        let matches =
+            move |d: &Device| !is_virtual(d) && (is_keyboard(d) || grab_mice && is_mouse(d));
+        let (watcher, helper) = match self.run_as.as_ref().map(|_| helper::fork()) {
+            None => (watcher, None),
+            Some(Ok(Fork::Remapper(helper))) => (None, Some(helper)),
+            Some(Ok(Fork::Root(root))) => {
+                // The remapper opens these again.
+                drop(keyboards);
+                drop(mice);
+                root.serve(watcher, known, matches)
+            }
+            Some(Err(e)) => {
+                eprintln!("Could not fork. {e}");
+                exit(1);
+            }
+        };
+        if let Err(e) = validate_emergency_stop_chord(
+            &self.emergency_stop_chord,
+            &[
+                ("release all", &self.release_all_chord),
+                ("pause", &self.pause_chord),
+                (
+                    "macro record",
+                    self.macros.as_ref().map_or(&[], |m| &m.record_chord),
+                ),
+                (
+                    "macro stop",
+                    self.macros.as_ref().map_or(&[], |m| &m.stop_chord),
+                ),
+                (
+                    "macro play",
+                    self.macros.as_ref().map_or(&[], |m| &m.play_chord),
+                ),
+            ],
+            &keyboards,
+        ) {
+            eprintln!("{e}");
+            exit(1);
+        }
+        let writer = match KeyWriter::new() {
+            Err(e) => {
+                match e.kind() {
+                    std::io::ErrorKind::PermissionDenied => {
+                        eprintln!("{e}");
+                        eprintln!(
+                            "Kiri has to be run with superuser privileges, \
+                            or by a user who can read /dev/input and write /dev/uinput, \
+                            e.g. a member of the input group with a udev rule for uinput. \
+                            Retry with sudo."
+                        );
+                    }
+                    _ => {
+                        eprintln!("{e}");
+                    }
+                };
+                exit(1)
+            }
+            Ok(writer) => SharedKeyWriter::new(writer),
+        };
+        log::info!("Config loaded");
+        log::info!(
+            "Press {:?} to ungrab keyboards and exit",
+            self.emergency_stop_chord
+        );
+        let (tx, rx) = channel();
+        if let Some(path) = &self.control_socket {
+            if let Err(e) = control::listen(path, tx.clone()) {
+                eprintln!("Could not listen on {}. {e}", path.display());
+                exit(1);
+            }
+        }
+        let tx = match &self.debounce {
+            // The emergency stop chord is checked before the debouncer, which may
+            // hold the keys back for a window.
+            Some(config) => debounce::spawn(config.clone(), self.emergency_stop_chord.clone(), tx),
+            None => tx,
+        };
+        let grabbed = make_read_channel(keyboards.into_iter().chain(mice), tx.clone());
+        if let Some(helper) = &helper {
+            if let Err(e) = helper.spawn(tx) {
+                log::error!("Hotplug is disabled. {e}");
+            }
+        } else if let Some(watcher) = watcher {
+            watcher.spawn(known, matches, tx);
+        }
+        if let Some(name) = &self.run_as {
+            let files = self
+                .state_hooks
+                .iter()
+                .filter_map(|h| match h {
+                    StateHook::StateFile(path) | StateHook::JsonLines(path) => Some(path.as_path()),
+                    StateHook::Command { .. } => None,
+                })
+                .chain(self.macros.as_ref().map(|m| m.file.as_path()));
+            if let Err(e) = switch_user(name, self.control_socket.as_deref(), files) {
+                eprintln!("Could not switch to {name}. {e}");
+                ungrab_devices(&grabbed);
+                exit(1);
+            }
+        }
+        let state_changes = hooks::spawn(self.state_hooks.clone());
+        let layers = &self.layers;
+        let layer_writer = writer.clone();
+        let mut dispatcher = Dispatcher {
+            devices: HashMap::default(),
+            stacks: HashMap::default(),
+            device_groups: &self.device_groups,
+            new_stack: Box::new(move || {
+                layers.to_key_recorder(&LayerContext {
+                    writer: layer_writer.clone(),
+                    state_changes: state_changes.clone(),
+                    clock: Clock::default(),
+                    run_commands: true,
+                })
+            }),
+            passthrough: writer.handle("pause"),
+            macros: self
+                .macros
+                .clone()
+                .map(|config| Macros::new(config, writer.clone())),
+            writer,
+            paused: false,
+            helper,
+        };
+        let mut pressed_keys = HashSet::default();
+        // Mouse movement until the next SYN_REPORT.
+        let mut motion = Vec::new();
+        for event in rx {
+            let (id, input_event) = match event {
+                Event::Input(id, input_event) => (id, input_event),
+                Event::Added { id, name, grabbed } => {
+                    dispatcher.add_device(id, name, grabbed);
+                    continue;
+                }
+                Event::Removed(id) => {
+                    dispatcher.remove_device(id, &mut pressed_keys);
+                    continue;
+                }
+                Event::Request(request) => {
+                    dispatcher.handle_request(request);
+                    continue;
+                }
+                Event::EmergencyStop => dispatcher.emergency_stop(),
+            };
+            match input_event.kind() {
+                InputEventKind::RelAxis(axis) if is_motion(axis) => motion.push(input_event),
+                InputEventKind::RelAxis(axis) => {
+                    if let Some((key, clicks)) = wheel_clicks(axis, input_event.value()) {
+                        for _ in 0..clicks {
+                            let time = input_event.timestamp();
+                            dispatcher.send_key(id, KeyInput::press(key), time);
+                            dispatcher.send_key(id, KeyInput::release(key), time);
+                        }
+                    }
+                }
+                InputEventKind::Synchronization(_) if !motion.is_empty() => {
+                    dispatcher.writer.move_pointer(std::mem::take(&mut motion));
+                }
+                _ => (),
+            }
+            if let InputEventKind::Key(key) = input_event.kind() {
+                if input_event.value() == 0 {
+                    pressed_keys.remove(&key);
+                } else {
+                    pressed_keys.insert(key);
+                }
+                if let Some(device) = dispatcher.devices.get_mut(&id) {
+                    if input_event.value() == 0 {
+                        device.pressed_keys.remove(&key);
+                    } else {
+                        device.pressed_keys.insert(key);
+                    }
+                }
+                if input_event.value() == 1 {
+                    if completes_chord(&self.emergency_stop_chord, key, &pressed_keys) {
+                        dispatcher.emergency_stop();
+                    }
+                    if completes_chord(&self.release_all_chord, key, &pressed_keys) {
+                        log::info!("Release all keys");
+                        dispatcher.reset_layers();
+                        dispatcher.passthrough.release_all();
+                        continue;
+                    }
+                    if completes_chord(&self.pause_chord, key, &pressed_keys) {
+                        dispatcher.set_paused(!dispatcher.paused);
+                        continue;
+                    }
+                    if let Some(macros) = &mut dispatcher.macros {
+                        if macros.handle_press(key, &pressed_keys) {
+                            continue;
+                        }
+                    }
+                }
+                let key = KeyInput(key, input_event.value().into());
+                dispatcher.send_key(id, key, input_event.timestamp());
+                if let Some(macros) = &mut dispatcher.macros {
+                    macros.play_pending(&pressed_keys);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::read_keys::{KeyInput, KeyInputKind};
+use crate::write_keys::SharedKeyWriter;
+use evdev::Key;
+use rustc_hash::FxHashSet as HashSet;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How the intervals between recorded keys are played back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroTiming {
+    /// As recorded.
+    Original,
+    /// As recorded, but no interval is longer than this.
+    Compressed(Duration),
+}
+
+/// Recording of the keys sent to the virtual keyboard and playing them back.
+#[derive(Debug, Clone)]
+pub struct MacroConfig {
+    /// Pressing all of these keys at once starts recording.
+    pub record_chord: Vec<Key>,
+    /// Pressing a digit while holding these keys stops recording into the slot of the digit.
+    pub stop_chord: Vec<Key>,
+    /// Pressing a digit while holding these keys plays the slot of the digit
+    /// once all keys are released.
+    pub play_chord: Vec<Key>,
+    pub timing: MacroTiming,
+    /// File keeping the slots across restarts.
+    pub file: PathBuf,
+}
+
+/// Keys and the interval before each of them.
+type Macro = Vec<(Duration, KeyInput)>;
+
+/// The writer sleeps this long after each key.
+const WRITE_INTERVAL: Duration = Duration::from_millis(5);
+
+fn slot_of(key: Key) -> Option<u8> {
+    match key {
+        Key::KEY_1 => Some(1),
+        Key::KEY_2 => Some(2),
+        Key::KEY_3 => Some(3),
+        Key::KEY_4 => Some(4),
+        Key::KEY_5 => Some(5),
+        Key::KEY_6 => Some(6),
+        Key::KEY_7 => Some(7),
+        Key::KEY_8 => Some(8),
+        Key::KEY_9 => Some(9),
+        Key::KEY_0 => Some(0),
+        _ => None,
+    }
+}
+
+/// Drop releases of keys pressed before the recording started and presses of
+/// keys still held when it stopped, e.g. the keys of the chords.
+fn trim(recording: Vec<(Instant, KeyInput)>) -> Macro {
+    let mut keep = vec![false; recording.len()];
+    let mut pressed = HashSet::default();
+    for (i, (_, key)) in recording.iter().enumerate() {
+        match key.1 {
+            KeyInputKind::Press => {
+                pressed.insert(key.0);
+            }
+            KeyInputKind::Release => keep[i] = pressed.remove(&key.0),
+        }
+    }
+    let mut released = HashSet::default();
+    for (i, (_, key)) in recording.iter().enumerate().rev() {
+        match key.1 {
+            KeyInputKind::Press => keep[i] = released.contains(&key.0),
+            KeyInputKind::Release => {
+                released.insert(key.0);
+            }
+        }
+    }
+    let mut previous = None;
+    recording
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|((time, key), _)| {
+            let interval = previous.map_or(Duration::ZERO, |p| time.duration_since(p));
+            previous = Some(time);
+            (interval, key)
+        })
+        .collect()
+}
+
+/// Read slots from lines like `1<TAB>interval in ms<TAB>KEY_A<TAB>press`.
+fn load(path: &Path) -> BTreeMap<u8, Macro> {
+    let mut slots = BTreeMap::<u8, Macro>::new();
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return slots,
+        Err(e) => {
+            log::warn!("{}: {e}", path.display());
+            return slots;
+        }
+    };
+    for (n, line) in source.lines().enumerate() {
+        let parsed = match line.split('\t').collect::<Vec<_>>().as_slice() {
+            [slot, interval, key, kind] => (|| {
+                let key = key.parse().ok()?;
+                let key = match *kind {
+                    "press" => KeyInput::press(key),
+                    "release" => KeyInput::release(key),
+                    _ => None?,
+                };
+                Some((
+                    slot.parse().ok()?,
+                    Duration::from_millis(interval.parse().ok()?),
+                    key,
+                ))
+            })(),
+            _ => None,
+        };
+        match parsed {
+            Some((slot, interval, key)) => slots.entry(slot).or_default().push((interval, key)),
+            None => log::warn!("{}:{}: invalid line", path.display(), n + 1),
+        }
+    }
+    slots
+}
+
+fn save(path: &Path, slots: &BTreeMap<u8, Macro>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for (slot, keys) in slots {
+        for (interval, key) in keys {
+            let kind = match key.1 {
+                KeyInputKind::Press => "press",
+                KeyInputKind::Release => "release",
+            };
+            writeln!(
+                file,
+                "{slot}\t{}\t{:?}\t{kind}",
+                interval.as_millis(),
+                key.0
+            )?;
+        }
+    }
+    fs::rename(tmp, path)
+}
+
+pub(crate) struct Macros {
+    config: MacroConfig,
+    slots: BTreeMap<u8, Macro>,
+    writer: SharedKeyWriter,
+    /// Slot to play once all keys are released, so that the chord does not
+    /// modify the played keys.
+    pending: Option<u8>,
+    playing: Arc<AtomicBool>,
+}
+
+impl Macros {
+    pub fn new(config: MacroConfig, writer: SharedKeyWriter) -> Self {
+        Self {
+            slots: load(&config.file),
+            config,
+            writer,
+            pending: None,
+            playing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Handle a press of `key` completing a chord. Returns whether it did.
+    pub fn handle_press(&mut self, key: Key, pressed_keys: &HashSet<Key>) -> bool {
+        if crate::completes_chord(&self.config.record_chord, key, pressed_keys) {
+            log::info!("Recording a macro");
+            self.writer.start_recording();
+            return true;
+        }
+        let held =
+            |chord: &[Key]| !chord.is_empty() && chord.iter().all(|k| pressed_keys.contains(k));
+        let Some(slot) = slot_of(key) else {
+            return false;
+        };
+        if held(&self.config.stop_chord) {
+            match self.writer.stop_recording() {
+                Some(recording) => {
+                    let keys = trim(recording);
+                    log::info!("Recorded {} keys into macro {slot}", keys.len());
+                    self.slots.insert(slot, keys);
+                    if let Err(e) = save(&self.config.file, &self.slots) {
+                        log::warn!("{}: {e}", self.config.file.display());
+                    }
+                }
+                None => log::warn!("Not recording a macro"),
+            }
+            true
+        } else if held(&self.config.play_chord) {
+            self.pending = Some(slot);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Play the pending macro if no keys are held.
+    pub fn play_pending(&mut self, pressed_keys: &HashSet<Key>) {
+        if !pressed_keys.is_empty() {
+            return;
+        }
+        let Some(slot) = self.pending.take() else {
+            return;
+        };
+        let Some(keys) = self.slots.get(&slot).cloned() else {
+            log::warn!("Macro {slot} is empty");
+            return;
+        };
+        if self.playing.swap(true, Ordering::SeqCst) {
+            log::warn!("Another macro is being played");
+            return;
+        }
+        log::info!("Playing macro {slot}");
+        let mut handle = self.writer.handle("macro");
+        let timing = self.config.timing;
+        let playing = self.playing.clone();
+        thread::spawn(move || {
+            for (interval, key) in keys {
+                let interval = match timing {
+                    MacroTiming::Original => interval,
+                    MacroTiming::Compressed(max) => interval.min(max),
+                };
+                thread::sleep(interval.saturating_sub(WRITE_INTERVAL));
+                handle.fire_key_input(key, None);
+            }
+            drop(handle);
+            playing.store(false, Ordering::SeqCst);
+        });
+    }
+}
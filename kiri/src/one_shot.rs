@@ -0,0 +1,180 @@
+use crate::clock::Clock;
+use crate::custom_layer::{CustomLayer, CustomLayers, Timer, ToCustomLayer};
+use crate::read_keys::{AddLayer, KeyInput, KeyInputKind, KeyReceiver, LayerContext};
+use evdev::Key;
+use rustc_hash::FxHashSet as HashSet;
+use std::time::{Duration, SystemTime};
+
+/// A layer making `keys` one-shot: a tapped key stays pressed until the next
+/// key is released. Modifiers become one-shot modifiers, and the triggers of
+/// layers active while a key is held become one-shot layers.
+/// Holding the keys works as usual.
+///
+/// The state is `Idle`, or `OneShot` followed by the tapped keys.
+#[derive(Debug, Clone)]
+pub struct OneShotLayer {
+    pub layer_name: &'static str,
+    pub keys: Vec<Key>,
+    /// Milli sec. after which the tapped keys are released if no key follows.
+    /// `None` waits forever.
+    pub timeout: Option<u32>,
+    /// Keys releasing the tapped keys instead of being sent.
+    /// Tapping a tapped key again also releases it.
+    pub cancel_keys: Vec<Key>,
+}
+
+struct OneShot<T> {
+    key_receiver: T,
+    layer: OneShotLayer,
+    /// Held keys of `keys` which will be one-shot if released before another key is pressed.
+    tapping: HashSet<Key>,
+    /// Tapped keys whose releases have not been sent yet.
+    pending: Vec<Key>,
+    /// The key pressed after the tapped keys. Its release releases them.
+    applied_to: Option<Key>,
+    /// The last tap, which the timer is set for.
+    last: Option<(KeyInput, SystemTime)>,
+    /// Keys whose presses were not sent, so their releases are not sent either.
+    swallowed: HashSet<Key>,
+    clock: Clock,
+}
+
+impl<T: KeyReceiver> OneShot<T> {
+    fn release_pending(&mut self, time: SystemTime) {
+        for k in self.pending.drain(..).rev() {
+            self.key_receiver.send_key(KeyInput::release(k), time);
+        }
+        self.applied_to = None;
+        self.last = None;
+    }
+}
+
+impl<T: KeyReceiver + 'static> CustomLayer for OneShot<T> {
+    fn name(&self) -> &'static str {
+        self.layer.layer_name
+    }
+
+    fn state(&self) -> String {
+        if self.pending.is_empty() {
+            return "Idle".to_string();
+        }
+        ["OneShot".to_string()]
+            .into_iter()
+            .chain(self.pending.iter().map(|k| {
+                let name = format!("{k:?}");
+                name.strip_prefix("KEY_").unwrap_or(&name).to_string()
+            }))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn set_state(&mut self, state: &str) -> Result<(), String> {
+        match state {
+            "Idle" => {
+                self.release_pending(self.clock.now());
+                Ok(())
+            }
+            _ => Err(format!(
+                "{} has no state {state}. states: [Idle]",
+                self.layer.layer_name
+            )),
+        }
+    }
+
+    fn send_key(&mut self, key: KeyInput, time: SystemTime, timer: &Timer) {
+        let KeyInput(k, kind) = key;
+        if kind == KeyInputKind::Release {
+            if self.swallowed.remove(&k) {
+                return;
+            }
+            if self.tapping.remove(&k) && !self.pending.contains(&k) {
+                log::debug!("[{}] one-shot {:?}", self.layer.layer_name, k);
+                self.pending.push(k);
+                self.last = Some((key, time));
+                if let Some(timeout) = self.layer.timeout {
+                    timer.set(key, time, Duration::from_millis(timeout.into()));
+                }
+                return;
+            }
+            self.key_receiver.send_key(key, time);
+            if self.applied_to == Some(k) {
+                self.release_pending(time);
+            }
+            return;
+        }
+        if self.swallowed.contains(&k) {
+            return;
+        }
+        if self.pending.contains(&k) {
+            log::debug!("[{}] cancel {:?}", self.layer.layer_name, k);
+            self.swallowed.insert(k);
+            self.pending.retain(|p| *p != k);
+            self.key_receiver.send_key(KeyInput::release(k), time);
+            if self.pending.is_empty() {
+                self.release_pending(time);
+            }
+            return;
+        }
+        if !self.pending.is_empty() && self.layer.cancel_keys.contains(&k) {
+            log::debug!("[{}] cancel", self.layer.layer_name);
+            self.swallowed.insert(k);
+            self.release_pending(time);
+            return;
+        }
+        if self.layer.keys.contains(&k) {
+            self.tapping.insert(k);
+        } else {
+            self.tapping.clear();
+            if !self.pending.is_empty() && self.applied_to.is_none() {
+                self.applied_to = Some(k);
+                self.last = None;
+            }
+        }
+        self.key_receiver.send_key(key, time);
+    }
+
+    fn timeout(&mut self, key: KeyInput, time: SystemTime, _: &Timer) {
+        if self.last == Some((key, time)) {
+            log::debug!("[{}] timeout", self.layer.layer_name);
+            // Stamped with the tap, so that the latency includes the wait.
+            self.release_pending(time);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.release_pending(self.clock.now());
+        self.tapping.clear();
+        self.swallowed.clear();
+    }
+
+    fn key_receiver(&mut self) -> &mut dyn KeyReceiver {
+        &mut self.key_receiver
+    }
+}
+
+impl ToCustomLayer for OneShotLayer {
+    fn to_custom_layer(
+        &self,
+        key_receiver: impl KeyReceiver + 'static,
+        context: &LayerContext,
+    ) -> impl CustomLayer {
+        OneShot {
+            key_receiver,
+            layer: self.clone(),
+            tapping: HashSet::default(),
+            pending: Vec::new(),
+            applied_to: None,
+            last: None,
+            swallowed: HashSet::default(),
+            clock: context.clock.clone(),
+        }
+    }
+}
+
+impl AddLayer for OneShotLayer {
+    type LayerAdded<A> = CustomLayers<Self, A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        CustomLayers(self, tail)
+    }
+}
@@ -0,0 +1,162 @@
+use crate::read_keys::{KeyInput, KeyInputKind};
+use evdev::Key;
+use std::fmt::{self, Debug};
+
+const LETTERS: [Key; 26] = [
+    Key::KEY_A,
+    Key::KEY_B,
+    Key::KEY_C,
+    Key::KEY_D,
+    Key::KEY_E,
+    Key::KEY_F,
+    Key::KEY_G,
+    Key::KEY_H,
+    Key::KEY_I,
+    Key::KEY_J,
+    Key::KEY_K,
+    Key::KEY_L,
+    Key::KEY_M,
+    Key::KEY_N,
+    Key::KEY_O,
+    Key::KEY_P,
+    Key::KEY_Q,
+    Key::KEY_R,
+    Key::KEY_S,
+    Key::KEY_T,
+    Key::KEY_U,
+    Key::KEY_V,
+    Key::KEY_W,
+    Key::KEY_X,
+    Key::KEY_Y,
+    Key::KEY_Z,
+];
+
+const DIGITS: [Key; 10] = [
+    Key::KEY_1,
+    Key::KEY_2,
+    Key::KEY_3,
+    Key::KEY_4,
+    Key::KEY_5,
+    Key::KEY_6,
+    Key::KEY_7,
+    Key::KEY_8,
+    Key::KEY_9,
+    Key::KEY_0,
+];
+
+const MODIFIERS: [Key; 8] = [
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTSHIFT,
+    Key::KEY_RIGHTSHIFT,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+];
+
+/// A set of keys matched by the entries of a [`RemapLayer`](crate::RemapLayer)
+/// with patterns, instead of an entry for each key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyPattern {
+    Any,
+    /// `KEY_A` to `KEY_Z`.
+    Letters,
+    /// `KEY_1` to `KEY_0` above the letters.
+    Digits,
+    /// Left and right Ctrl, Shift, Alt and Meta.
+    Modifiers,
+    Keys(Vec<Key>),
+    /// The keys of the pattern other than the listed ones.
+    Except(Box<KeyPattern>, Vec<Key>),
+}
+
+impl KeyPattern {
+    /// The keys of this pattern other than `keys`.
+    pub fn except(self, keys: &[Key]) -> KeyPattern {
+        KeyPattern::Except(Box::new(self), keys.to_vec())
+    }
+
+    pub fn matches(&self, key: Key) -> bool {
+        match self {
+            KeyPattern::Any => true,
+            KeyPattern::Letters => LETTERS.contains(&key),
+            KeyPattern::Digits => DIGITS.contains(&key),
+            KeyPattern::Modifiers => MODIFIERS.contains(&key),
+            KeyPattern::Keys(keys) => keys.contains(&key),
+            KeyPattern::Except(pattern, keys) => !keys.contains(&key) && pattern.matches(key),
+        }
+    }
+}
+
+/// Presses or releases of the keys of a [`KeyPattern`].
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PatternInput(pub(crate) KeyPattern, pub(crate) KeyInputKind);
+
+impl PatternInput {
+    pub fn press(pattern: KeyPattern) -> PatternInput {
+        Self(pattern, KeyInputKind::Press)
+    }
+
+    pub fn release(pattern: KeyPattern) -> PatternInput {
+        Self(pattern, KeyInputKind::Release)
+    }
+
+    pub(crate) fn matches(&self, key: KeyInput) -> bool {
+        self.1 == key.1 && self.0.matches(key.0)
+    }
+}
+
+impl Debug for PatternInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let PatternInput(pattern, kind) = self;
+        write!(
+            f,
+            "{:?} {}",
+            pattern,
+            match kind {
+                KeyInputKind::Press => "↓",
+                KeyInputKind::Release => "↑",
+            }
+        )
+    }
+}
+
+/// An output key of an entry with a pattern.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PatternOutput {
+    Key(KeyInput),
+    /// Press the key matched by the pattern.
+    PressMatched,
+    /// Release the key matched by the pattern.
+    ReleaseMatched,
+}
+
+impl PatternOutput {
+    pub fn press(key: Key) -> PatternOutput {
+        Self::Key(KeyInput::press(key))
+    }
+
+    pub fn release(key: Key) -> PatternOutput {
+        Self::Key(KeyInput::release(key))
+    }
+
+    /// The key output when `matched` has been matched.
+    pub(crate) fn resolve(self, matched: Key) -> KeyInput {
+        match self {
+            PatternOutput::Key(key) => key,
+            PatternOutput::PressMatched => KeyInput::press(matched),
+            PatternOutput::ReleaseMatched => KeyInput::release(matched),
+        }
+    }
+}
+
+impl Debug for PatternOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternOutput::Key(key) => write!(f, "{key:?}"),
+            PatternOutput::PressMatched => write!(f, "matched ↓"),
+            PatternOutput::ReleaseMatched => write!(f, "matched ↑"),
+        }
+    }
+}
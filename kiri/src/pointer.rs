@@ -0,0 +1,228 @@
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, EventType, InputEvent, Key, RelativeAxisType};
+use rustc_hash::FxHashSet as HashSet;
+use std::io;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Key codes not assigned in linux/input-event-codes.h, used as keys which
+// move the pointer or scroll while they are pressed.
+pub const POINTER_UP: Key = Key::new(0x2f0);
+pub const POINTER_DOWN: Key = Key::new(0x2f1);
+pub const POINTER_LEFT: Key = Key::new(0x2f2);
+pub const POINTER_RIGHT: Key = Key::new(0x2f3);
+pub const SCROLL_UP: Key = Key::new(0x2f4);
+pub const SCROLL_DOWN: Key = Key::new(0x2f5);
+pub const SCROLL_LEFT: Key = Key::new(0x2f6);
+pub const SCROLL_RIGHT: Key = Key::new(0x2f7);
+// A press of these is a wheel click, both from grabbed mice and to the virtual pointer.
+pub const WHEEL_UP: Key = Key::new(0x2f8);
+pub const WHEEL_DOWN: Key = Key::new(0x2f9);
+pub const WHEEL_LEFT: Key = Key::new(0x2fa);
+pub const WHEEL_RIGHT: Key = Key::new(0x2fb);
+
+const POINTER_KEYS: [(&str, Key); 12] = [
+    ("POINTER_UP", POINTER_UP),
+    ("POINTER_DOWN", POINTER_DOWN),
+    ("POINTER_LEFT", POINTER_LEFT),
+    ("POINTER_RIGHT", POINTER_RIGHT),
+    ("SCROLL_UP", SCROLL_UP),
+    ("SCROLL_DOWN", SCROLL_DOWN),
+    ("SCROLL_LEFT", SCROLL_LEFT),
+    ("SCROLL_RIGHT", SCROLL_RIGHT),
+    ("WHEEL_UP", WHEEL_UP),
+    ("WHEEL_DOWN", WHEEL_DOWN),
+    ("WHEEL_LEFT", WHEEL_LEFT),
+    ("WHEEL_RIGHT", WHEEL_RIGHT),
+];
+
+/// Buttons sent through the virtual pointer instead of the virtual keyboard.
+const BUTTONS: [Key; 8] = [
+    Key::BTN_LEFT,
+    Key::BTN_RIGHT,
+    Key::BTN_MIDDLE,
+    Key::BTN_SIDE,
+    Key::BTN_EXTRA,
+    Key::BTN_FORWARD,
+    Key::BTN_BACK,
+    Key::BTN_TASK,
+];
+
+pub(crate) const POINTER_NAME: &str = "kiri virtual pointer";
+
+const WHEEL_KEYS: [Key; 4] = [WHEEL_UP, WHEEL_DOWN, WHEEL_LEFT, WHEEL_RIGHT];
+
+/// Interval of the pointer movements.
+const TICK: Duration = Duration::from_millis(10);
+/// Pixels per tick when a key starts moving the pointer.
+const MIN_SPEED: f64 = 2.0;
+/// Pixels per tick after [`ACCELERATION`].
+const MAX_SPEED: f64 = 20.0;
+/// Time until the pointer reaches [`MAX_SPEED`].
+const ACCELERATION: Duration = Duration::from_millis(600);
+/// Interval of the wheel clicks while a scroll key is held.
+const SCROLL_INTERVAL: Duration = Duration::from_millis(60);
+
+pub(crate) fn pointer_key(name: &str) -> Option<Key> {
+    POINTER_KEYS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, k)| *k)
+}
+
+/// Whether `key` is sent through the virtual pointer.
+pub(crate) fn is_pointer_key(key: Key) -> bool {
+    BUTTONS.contains(&key) || POINTER_KEYS.iter().any(|(_, k)| *k == key)
+}
+
+/// The wheel key and the number of its clicks for a wheel event of a mouse.
+pub(crate) fn wheel_clicks(axis: RelativeAxisType, value: i32) -> Option<(Key, u32)> {
+    let key = match (axis, value > 0) {
+        (RelativeAxisType::REL_WHEEL, true) => WHEEL_UP,
+        (RelativeAxisType::REL_WHEEL, false) => WHEEL_DOWN,
+        (RelativeAxisType::REL_HWHEEL, true) => WHEEL_RIGHT,
+        (RelativeAxisType::REL_HWHEEL, false) => WHEEL_LEFT,
+        _ => return None,
+    };
+    Some((key, value.unsigned_abs()))
+}
+
+/// Whether `axis` is the movement of a mouse, which is passed on as it is.
+pub(crate) fn is_motion(axis: RelativeAxisType) -> bool {
+    axis == RelativeAxisType::REL_X || axis == RelativeAxisType::REL_Y
+}
+
+fn emit(device: &mut VirtualDevice, events: &[InputEvent]) {
+    if let Err(e) = device.emit(events) {
+        log::error!("Could not write to the virtual pointer. {e}");
+    }
+}
+
+fn relative(axis: RelativeAxisType, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE, axis.0, value)
+}
+
+/// Move the pointer and scroll for `held` keys.
+fn tick(
+    device: &mut VirtualDevice,
+    held: &HashSet<Key>,
+    since: Instant,
+    scrolled: &mut Option<Instant>,
+) {
+    let direction = |minus, plus| held.contains(&plus) as i32 - held.contains(&minus) as i32;
+    let (x, y) = (
+        direction(POINTER_LEFT, POINTER_RIGHT),
+        direction(POINTER_UP, POINTER_DOWN),
+    );
+    let mut events = Vec::new();
+    if x != 0 || y != 0 {
+        let t = (since.elapsed().as_secs_f64() / ACCELERATION.as_secs_f64()).min(1.0);
+        let speed = (MIN_SPEED + (MAX_SPEED - MIN_SPEED) * t).round() as i32;
+        events.push(relative(RelativeAxisType::REL_X, x * speed));
+        events.push(relative(RelativeAxisType::REL_Y, y * speed));
+    }
+    let (h, v) = (
+        direction(SCROLL_LEFT, SCROLL_RIGHT),
+        direction(SCROLL_DOWN, SCROLL_UP),
+    );
+    if (h != 0 || v != 0) && scrolled.is_none_or(|s| s.elapsed() >= SCROLL_INTERVAL) {
+        *scrolled = Some(Instant::now());
+        events.push(relative(RelativeAxisType::REL_HWHEEL, h));
+        events.push(relative(RelativeAxisType::REL_WHEEL, v));
+    }
+    if !events.is_empty() {
+        emit(device, &events);
+    }
+}
+
+enum PointerInput {
+    Key(Key, bool),
+    /// Movement of a grabbed mouse.
+    Motion(Vec<InputEvent>),
+}
+
+/// A virtual relative pointer driven by [`is_pointer_key`] keys.
+pub(crate) struct Pointer {
+    tx: Sender<PointerInput>,
+}
+
+impl Pointer {
+    pub fn new() -> io::Result<Pointer> {
+        let mut buttons = AttributeSet::<Key>::new();
+        for b in BUTTONS {
+            buttons.insert(b);
+        }
+        let mut axes = AttributeSet::<RelativeAxisType>::new();
+        for a in [
+            RelativeAxisType::REL_X,
+            RelativeAxisType::REL_Y,
+            RelativeAxisType::REL_WHEEL,
+            RelativeAxisType::REL_HWHEEL,
+        ] {
+            axes.insert(a);
+        }
+        let mut device = VirtualDeviceBuilder::new()?
+            .name(POINTER_NAME.as_bytes())
+            .with_keys(&buttons)?
+            .with_relative_axes(&axes)?
+            .build()?;
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut held = HashSet::default();
+            // When the pointer started moving.
+            let mut since = Instant::now();
+            let mut scrolled = None;
+            loop {
+                let received = if held.is_empty() {
+                    rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    rx.recv_timeout(TICK)
+                };
+                match received {
+                    Ok(PointerInput::Motion(events)) => emit(&mut device, &events),
+                    Ok(PointerInput::Key(key, pressed)) if BUTTONS.contains(&key) => {
+                        emit(
+                            &mut device,
+                            &[InputEvent::new(EventType::KEY, key.code(), pressed as i32)],
+                        );
+                    }
+                    Ok(PointerInput::Key(key, true)) if WHEEL_KEYS.contains(&key) => {
+                        let (axis, value) = match key {
+                            WHEEL_UP => (RelativeAxisType::REL_WHEEL, 1),
+                            WHEEL_DOWN => (RelativeAxisType::REL_WHEEL, -1),
+                            WHEEL_RIGHT => (RelativeAxisType::REL_HWHEEL, 1),
+                            _ => (RelativeAxisType::REL_HWHEEL, -1),
+                        };
+                        emit(&mut device, &[relative(axis, value)]);
+                    }
+                    Ok(PointerInput::Key(key, true)) => {
+                        if held.is_empty() {
+                            since = Instant::now();
+                        }
+                        if held.insert(key) {
+                            tick(&mut device, &held, since, &mut scrolled);
+                        }
+                    }
+                    Ok(PointerInput::Key(key, _)) => {
+                        held.remove(&key);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        tick(&mut device, &held, since, &mut scrolled)
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Ok(Pointer { tx })
+    }
+
+    pub fn send(&self, key: Key, pressed: bool) {
+        let _ = self.tx.send(PointerInput::Key(key, pressed));
+    }
+
+    /// Pass on `events` of [`is_motion`] axes.
+    pub fn send_motion(&self, events: Vec<InputEvent>) {
+        let _ = self.tx.send(PointerInput::Motion(events));
+    }
+}
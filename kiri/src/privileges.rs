@@ -0,0 +1,111 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The smallest uid of a person, `UID_MIN` of login.defs.
+const UID_MIN: libc::uid_t = 1000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub groups: Vec<libc::gid_t>,
+    pub home: PathBuf,
+}
+
+impl User {
+    fn from_passwd(pw: *const libc::passwd) -> io::Result<User> {
+        if pw.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such user"));
+        }
+        let pw = unsafe { &*pw };
+        let name = unsafe { CStr::from_ptr(pw.pw_name) };
+        let home = unsafe { CStr::from_ptr(pw.pw_dir) };
+        let mut groups = vec![0; 64];
+        let mut n = groups.len() as libc::c_int;
+        while unsafe { libc::getgrouplist(name.as_ptr(), pw.pw_gid, groups.as_mut_ptr(), &mut n) }
+            == -1
+        {
+            groups.resize(n as usize, 0);
+        }
+        groups.truncate(n as usize);
+        Ok(User {
+            name: name.to_string_lossy().into_owned(),
+            uid: pw.pw_uid,
+            gid: pw.pw_gid,
+            groups,
+            home: PathBuf::from(home.to_string_lossy().into_owned()),
+        })
+    }
+
+    pub fn by_name(name: &str) -> io::Result<User> {
+        let name = CString::new(name).map_err(io::Error::other)?;
+        User::from_passwd(unsafe { libc::getpwnam(name.as_ptr()) })
+    }
+
+    pub fn by_uid(uid: libc::uid_t) -> io::Result<User> {
+        User::from_passwd(unsafe { libc::getpwuid(uid) })
+    }
+
+    /// The user running this process.
+    pub fn current() -> io::Result<User> {
+        User::by_uid(unsafe { libc::geteuid() })
+    }
+
+    /// The user of `name`, or else `SUDO_USER`, or else the owner of
+    /// the only session of a person in `/run/user`.
+    pub fn find(name: Option<&str>) -> io::Result<User> {
+        if let Some(name) = name
+            .map(str::to_string)
+            .or_else(|| std::env::var("SUDO_USER").ok())
+        {
+            return User::by_name(&name);
+        }
+        let mut sessions = fs::read_dir("/run/user")?
+            .map(|e| e?.metadata().map(|m| m.uid()))
+            .collect::<io::Result<Vec<_>>>()?;
+        // Leave out system users like the one of the display manager.
+        sessions.retain(|uid| *uid >= UID_MIN);
+        match sessions.as_slice() {
+            [uid] => User::by_uid(*uid),
+            [] => Err(io::Error::other("no one is logged in")),
+            _ => Err(io::Error::other(
+                "several users are logged in. Configure the user",
+            )),
+        }
+    }
+
+    /// Switch the process to this user for good.
+    /// Only async-signal-safe calls are made, so it can run after `fork`.
+    pub fn switch(&self) -> io::Result<()> {
+        // The supplementary groups have to be set while still root.
+        if unsafe {
+            libc::setgroups(self.groups.len(), self.groups.as_ptr()) == -1
+                || libc::setgid(self.gid) == -1
+                || libc::setuid(self.uid) == -1
+        } {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Create the directory of `file` for `user` if it does not exist,
+/// so that `user` can replace the file.
+pub(crate) fn prepare_dir(file: &Path, user: &User) -> io::Result<()> {
+    let Some(dir) = file.parent() else {
+        return Ok(());
+    };
+    if dir.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    std::os::unix::fs::chown(dir, Some(user.uid), Some(user.gid))
+}
@@ -0,0 +1,1522 @@
+use crate::clock::Clock;
+use crate::debounce::DebounceConfig;
+use crate::hooks::{StateChange, StateHook};
+use crate::macros::MacroConfig;
+use crate::pattern::{PatternInput, PatternOutput};
+use crate::write_keys::{KeyWriterHandle, SharedKeyWriter};
+use evdev::Key;
+use rustc_hash::FxHashMap as HashMap;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::SystemTime;
+use std::{thread, time};
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct PairRemapEntry<State> {
+    /// Condition of remapping
+    pub condition: State,
+    pub input: [KeyInput; 2],
+    /// Key sequence to be output
+    pub output: Vec<KeyInput>,
+    /// Next state
+    pub transition: State,
+    /// Threshold to judge simultaneous input. milli sec.
+    pub threshold: u32,
+    /// Keep the output keys pressed and not released held while both input
+    /// keys are held, and release them when either is released, so that
+    /// the pair works as a modifier. Otherwise they are released with the
+    /// input key pressed later.
+    pub hold: bool,
+}
+
+impl<T: Clone> PairRemapEntry<T> {
+    pub fn order_insensitive(self) -> impl Iterator<Item = Self> {
+        [
+            self.clone(),
+            Self {
+                input: [self.input[1], self.input[0]],
+                ..self
+            },
+        ]
+        .into_iter()
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, PartialOrd, Ord)]
+pub enum KeyInputKind {
+    Press,
+    Release,
+}
+
+impl From<KeyInputKind> for i32 {
+    fn from(k: KeyInputKind) -> Self {
+        match k {
+            KeyInputKind::Press => 1,
+            KeyInputKind::Release => 0,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct KeyInput(pub(crate) Key, pub(crate) KeyInputKind);
+
+impl KeyInput {
+    pub fn press(key: Key) -> KeyInput {
+        Self(key, KeyInputKind::Press)
+    }
+
+    pub fn release(key: Key) -> KeyInput {
+        Self(key, KeyInputKind::Release)
+    }
+}
+
+impl Debug for KeyInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use KeyInputKind::*;
+        let KeyInput(key, input_kind) = self;
+        write!(
+            f,
+            "{:?} {}",
+            key,
+            match input_kind {
+                Press => "↓",
+                Release => "↑",
+            }
+        )
+    }
+}
+
+impl From<i32> for KeyInputKind {
+    fn from(i: i32) -> Self {
+        match i {
+            0 => Self::Release,
+            1 | 2 => Self::Press,
+            _ => panic!("unknown input_event value"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct SingleRemapEntry<State> {
+    /// Condition of remapping
+    pub condition: State,
+    pub input: KeyInput,
+    /// Key sequence to be output
+    pub output: Vec<KeyInput>,
+    /// Next state
+    pub transition: State,
+}
+
+/// A [`SingleRemapEntry`] for each key matched by `input`, whose output can
+/// refer to the matched key. Used for keys without a [`SingleRemapEntry`] of their own.
+/// If several patterns match, the first one is used.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct SinglePatternEntry<State> {
+    /// Condition of remapping
+    pub condition: State,
+    pub input: PatternInput,
+    /// Key sequence to be output
+    pub output: Vec<PatternOutput>,
+    /// Next state
+    pub transition: State,
+}
+
+/// A [`PairRemapEntry`] for each key matched by the first input, to which
+/// [`PatternOutput::PressMatched`] and [`PatternOutput::ReleaseMatched`] refer.
+/// Used for pairs without a [`PairRemapEntry`] of their own.
+/// If several patterns match, the first one is used.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct PairPatternEntry<State> {
+    /// Condition of remapping
+    pub condition: State,
+    pub input: (PatternInput, KeyInput),
+    /// Key sequence to be output
+    pub output: Vec<PatternOutput>,
+    /// Next state
+    pub transition: State,
+    /// Threshold to judge simultaneous input. milli sec.
+    pub threshold: u32,
+    /// As in [`PairRemapEntry`].
+    pub hold: bool,
+}
+
+/// Taps of `input` counted while each press follows the previous press or
+/// release within `threshold` milli sec.
+/// The count is settled when `taps.len()` taps are counted, when the threshold
+/// passes, or when another key is pressed.
+/// Output keys pressed and not released are released with `input`,
+/// so they are held if `input` is held when the count is settled.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct TapDanceEntry<State> {
+    /// Condition of remapping
+    pub condition: State,
+    pub input: Key,
+    /// Key sequence to be output and next state for one tap, two taps, ...
+    pub taps: Vec<(Vec<KeyInput>, State)>,
+    pub threshold: u32,
+}
+
+/// A layer of entries, looked up by the state and the input key when a key arrives.
+/// Entries for the same state and input are logged when the layer starts,
+/// and the last of them is used.
+/// A key starting pairs waits for the longest threshold of all pairs in the layer.
+#[derive(PartialEq, Eq, Clone)]
+pub struct RemapLayer<State> {
+    pub pair_remap_entries: Vec<PairRemapEntry<State>>,
+    pub single_remap_entries: Vec<SingleRemapEntry<State>>,
+    pub tap_dance_entries: Vec<TapDanceEntry<State>>,
+    pub single_pattern_entries: Vec<SinglePatternEntry<State>>,
+    pub pair_pattern_entries: Vec<PairPatternEntry<State>>,
+    pub layer_name: &'static str,
+    pub initial_state: State,
+}
+
+impl Default for RemapLayer<()> {
+    fn default() -> Self {
+        Self {
+            pair_remap_entries: Default::default(),
+            single_remap_entries: Default::default(),
+            tap_dance_entries: Default::default(),
+            single_pattern_entries: Default::default(),
+            pair_pattern_entries: Default::default(),
+            layer_name: Default::default(),
+            initial_state: Default::default(),
+        }
+    }
+}
+
+impl<State: Debug> fmt::Debug for RemapLayer<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pair_remap_entries: ")?;
+        for e in &self.pair_remap_entries {
+            writeln!(f, "    {:?}", e)?;
+        }
+        write!(f, "single_remap_entries: ")?;
+        for e in &self.single_remap_entries {
+            write!(f, "\n    {:?}", e)?;
+        }
+        write!(f, "\ntap_dance_entries: ")?;
+        for e in &self.tap_dance_entries {
+            write!(f, "\n    {:?}", e)?;
+        }
+        write!(f, "\nsingle_pattern_entries: ")?;
+        for e in &self.single_pattern_entries {
+            write!(f, "\n    {:?}", e)?;
+        }
+        write!(f, "\npair_pattern_entries: ")?;
+        for e in &self.pair_pattern_entries {
+            write!(f, "\n    {:?}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl<State: Debug> fmt::Debug for PairRemapEntry<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, {:?}, {:?}, {:?}",
+            self.condition, self.input, self.output, self.transition
+        )
+    }
+}
+
+impl<State: Debug> fmt::Debug for SingleRemapEntry<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, {:?}, {:?}, {:?}",
+            self.condition, self.input, self.output, self.transition,
+        )
+    }
+}
+
+impl<State: Debug> fmt::Debug for SinglePatternEntry<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, {:?}, {:?}, {:?}",
+            self.condition, self.input, self.output, self.transition,
+        )
+    }
+}
+
+impl<State: Debug> fmt::Debug for PairPatternEntry<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, {:?}, {:?}, {:?}",
+            self.condition, self.input, self.output, self.transition
+        )
+    }
+}
+
+impl<State: Debug> fmt::Debug for TapDanceEntry<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, {:?}, {:?}, {:?}",
+            self.condition, self.input, self.taps, self.threshold,
+        )
+    }
+}
+
+type KeyEv = (KeyInput, SystemTime);
+
+#[derive(Debug)]
+pub(crate) enum KeyRecorderBehavior {
+    /// A timer set for a key and its time has reached the given deadline.
+    FireSpecificWaitingKey(KeyEv, SystemTime),
+    SendKey((KeyInput, SystemTime)),
+    Reset,
+    /// Reset and end the thread of the layer.
+    Stop,
+    Control(LayerControl),
+}
+
+/// The messages to a layer. A timeout is handled after the keys queued behind it
+/// which happened before its deadline, so that whether a key comes before
+/// a timeout depends on the timestamps, not on which message arrives first.
+pub(crate) struct Inbox {
+    rx: Receiver<KeyRecorderBehavior>,
+    /// Messages received ahead of a timeout.
+    queued: VecDeque<KeyRecorderBehavior>,
+}
+
+impl Inbox {
+    fn new(rx: Receiver<KeyRecorderBehavior>) -> Inbox {
+        Inbox {
+            rx,
+            queued: VecDeque::new(),
+        }
+    }
+}
+
+impl Iterator for Inbox {
+    type Item = KeyRecorderBehavior;
+
+    fn next(&mut self) -> Option<KeyRecorderBehavior> {
+        let received = match self.queued.pop_front() {
+            Some(received) => received,
+            None => self.rx.recv().ok()?,
+        };
+        let KeyRecorderBehavior::FireSpecificWaitingKey(_, deadline) = received else {
+            return Some(received);
+        };
+        self.queued.extend(self.rx.try_iter());
+        let early = self
+            .queued
+            .iter()
+            .take_while(
+                |m| matches!(m, KeyRecorderBehavior::SendKey((_, time)) if *time <= deadline),
+            )
+            .count();
+        if early == 0 {
+            return Some(received);
+        }
+        self.queued.insert(early, received);
+        self.queued.pop_front()
+    }
+}
+
+/// Status of a layer reported to the control socket.
+#[derive(Debug, Clone)]
+pub(crate) struct LayerStatus {
+    pub name: &'static str,
+    pub state: String,
+    pub enabled: bool,
+}
+
+/// A request passed along the layers until it reaches the layer named in it.
+#[derive(Debug)]
+pub(crate) enum LayerControl {
+    /// Collect the status of every layer.
+    Query(Vec<LayerStatus>, Sender<Vec<LayerStatus>>),
+    SetState {
+        layer: String,
+        state: String,
+        reply: Sender<Result<(), String>>,
+    },
+    SetEnabled {
+        layer: String,
+        enabled: bool,
+        reply: Sender<Result<(), String>>,
+    },
+}
+
+fn fire_waiting_key_with_delay(
+    key: (KeyInput, SystemTime),
+    tx: Sender<KeyRecorderBehavior>,
+    threshold: u64,
+    clock: &Clock,
+) {
+    // Counted from when the key was pressed, not from when it was read.
+    let deadline = key.1 + time::Duration::from_millis(threshold);
+    clock.at(deadline, move || {
+        // The layer may have stopped meanwhile.
+        let _ = tx.send(KeyRecorderBehavior::FireSpecificWaitingKey(key, deadline));
+    });
+}
+
+pub struct KeyRecorder {
+    tx: Sender<KeyRecorderBehavior>,
+    layer_name: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+struct Action<State> {
+    output_keys: Vec<KeyInput>,
+    transition: State,
+}
+
+pub trait KeyReceiver: Send {
+    fn send_key(&mut self, key: KeyInput, time: SystemTime);
+    /// Release every key held by this receiver and the ones after it,
+    /// and go back to the initial state.
+    fn reset(&mut self);
+    /// Reset, and end the threads of this receiver and the ones after it.
+    fn stop(&mut self);
+    fn control(&mut self, message: LayerControl);
+}
+
+/// Send `key` to the next receiver, remembering which input press caused
+/// each output press so that it can be released later.
+fn emit_key<T, State>(
+    key: KeyInput,
+    trigger: Option<Key>,
+    time: SystemTime,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    let KeyInput(output, kind) = key;
+    match kind {
+        KeyInputKind::Press => {
+            if !recorder_state.held_keys.contains(&(trigger, output)) {
+                recorder_state.held_keys.push((trigger, output));
+            }
+        }
+        KeyInputKind::Release => recorder_state.held_keys.retain(|(_, k)| *k != output),
+    }
+    recorder_state.key_receiver.send_key(key, time);
+}
+
+/// Release the output keys which were pressed because of `trigger`
+/// and have not been released yet.
+fn release_held_by<T, State>(
+    trigger: Key,
+    time: SystemTime,
+    layer_name: &'static str,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    let mut partners = Vec::new();
+    recorder_state.held_pairs.retain(|&(first, later)| {
+        if first == trigger {
+            partners.push(later);
+        }
+        first != trigger && later != trigger
+    });
+    for k in partners {
+        release_held_by(k, time, layer_name, recorder_state);
+    }
+    let mut released = Vec::new();
+    recorder_state.held_keys.retain(|&(t, k)| {
+        if t == Some(trigger) {
+            released.push(k);
+            false
+        } else {
+            true
+        }
+    });
+    for k in released.into_iter().rev() {
+        if recorder_state.held_keys.iter().all(|(_, h)| *h != k) {
+            log::debug!("[{}] release {:?} held by {:?}", layer_name, k, trigger);
+            recorder_state
+                .key_receiver
+                .send_key(KeyInput::release(k), time);
+        }
+    }
+}
+
+fn set_state<T, State: Eq + Copy + Debug + Hash>(
+    state: State,
+    note: &str,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    if state != recorder_state.state {
+        log::debug!(
+            "[{}] state : {:?} =====> {:?}{}",
+            recorder_info.layer_name,
+            recorder_state.state,
+            state,
+            note
+        );
+        let _ = recorder_info.state_changes.send(StateChange {
+            layer: recorder_info.layer_name,
+            previous: Some(format!("{:?}", recorder_state.state)),
+            state: format!("{state:?}"),
+        });
+        recorder_state.state = state;
+    }
+}
+
+fn perform_action<T, State: Eq + Copy + Debug + Hash>(
+    action: &Action<State>,
+    trigger: Option<Key>,
+    time: SystemTime,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    for output_key in &action.output_keys {
+        emit_key(*output_key, trigger, time, recorder_state);
+    }
+    set_state(action.transition, "", recorder_info, recorder_state);
+}
+
+fn fire_key_input<T, State: Eq + Copy + Debug + Hash>(
+    key: KeyInput,
+    time: SystemTime,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    let trigger = (key.1 == KeyInputKind::Press).then_some(key.0);
+    if let Some(action) = recorder_info.single_action(key, recorder_state.state) {
+        perform_action(&action, trigger, time, recorder_info, recorder_state);
+    } else {
+        emit_key(key, trigger, time, recorder_state);
+    }
+    if key.1 == KeyInputKind::Release {
+        release_held_by(key.0, time, recorder_info.layer_name, recorder_state);
+    }
+}
+
+/// Release the keys held by this layer and go back to the initial state.
+fn reset_layer<T, State: Eq + Copy + Debug + Hash>(
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    recorder_state.waiting_key = None;
+    recorder_state.dance = None;
+    recorder_state.held_pairs.clear();
+    let time = recorder_info.clock.now();
+    let mut released = Vec::new();
+    for (_, k) in recorder_state.held_keys.drain(..).rev() {
+        if !released.contains(&k) {
+            released.push(k);
+            recorder_state
+                .key_receiver
+                .send_key(KeyInput::release(k), time);
+        }
+    }
+    set_state(
+        recorder_info.initial_state,
+        " (reset)",
+        recorder_info,
+        recorder_state,
+    );
+}
+
+fn reset_handler<T, State: Eq + Copy + Debug + Hash>(
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    reset_layer(recorder_info, recorder_state);
+    recorder_state.key_receiver.reset();
+}
+
+fn control_handler<T, State: Eq + Copy + Debug + Hash>(
+    message: LayerControl,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    match message {
+        LayerControl::Query(mut statuses, reply) => {
+            statuses.push(LayerStatus {
+                name: recorder_info.layer_name,
+                state: format!("{:?}", recorder_state.state),
+                enabled: recorder_state.enabled,
+            });
+            recorder_state
+                .key_receiver
+                .control(LayerControl::Query(statuses, reply));
+        }
+        LayerControl::SetState {
+            layer,
+            state,
+            reply,
+        } if layer == recorder_info.layer_name => {
+            let result = match recorder_info
+                .states
+                .iter()
+                .find(|s| format!("{s:?}") == state)
+            {
+                Some(s) => {
+                    set_state(*s, " (forced)", recorder_info, recorder_state);
+                    Ok(())
+                }
+                None => Err(format!(
+                    "{} has no state {state}. states: {:?}",
+                    recorder_info.layer_name, recorder_info.states
+                )),
+            };
+            let _ = reply.send(result);
+        }
+        LayerControl::SetEnabled {
+            layer,
+            enabled,
+            reply,
+        } if layer == recorder_info.layer_name => {
+            if enabled != recorder_state.enabled {
+                if !enabled {
+                    reset_layer(recorder_info, recorder_state);
+                }
+                log::info!(
+                    "[{}] {}",
+                    recorder_info.layer_name,
+                    if enabled { "enabled" } else { "disabled" }
+                );
+                recorder_state.enabled = enabled;
+            }
+            let _ = reply.send(Ok(()));
+        }
+        message => recorder_state.key_receiver.control(message),
+    }
+}
+
+fn fire_specific_waiting_key_handler<T, State: Eq + Copy + Debug + Hash>(
+    key: KeyInput,
+    time: SystemTime,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    if Some((key, time)) == recorder_state.waiting_key {
+        recorder_state.waiting_key = None;
+        fire_key_input(key, time, recorder_info, recorder_state);
+    } else if recorder_state
+        .dance
+        .as_ref()
+        .is_some_and(|d| d.key == key.0 && d.last == time)
+    {
+        // Stamped with the last tap, so that the latency includes the wait.
+        settle_dance(time, recorder_info, recorder_state);
+    }
+}
+
+/// Perform the action for the number of taps counted.
+fn settle_dance<T, State: Eq + Copy + Debug + Hash>(
+    time: SystemTime,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    if let Some(dance) = recorder_state.dance.take() {
+        let actions = &recorder_info.tap_dance_map[&(dance.key, dance.condition)].actions;
+        log::debug!(
+            "[{}] {} taps of {:?}",
+            recorder_info.layer_name,
+            dance.count,
+            dance.key
+        );
+        perform_action(
+            &actions[dance.count - 1],
+            Some(dance.key),
+            time,
+            recorder_info,
+            recorder_state,
+        );
+        if !dance.held {
+            release_held_by(dance.key, time, recorder_info.layer_name, recorder_state);
+        }
+    }
+}
+
+/// Count a press or release of the key being tapped.
+fn count_tap<T, State: Eq + Copy + Debug + Hash>(
+    key: KeyInput,
+    time: SystemTime,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+    tx: &Sender<KeyRecorderBehavior>,
+) where
+    T: KeyReceiver,
+{
+    let dance = recorder_state.dance.as_mut().unwrap();
+    match key.1 {
+        // Repeats
+        KeyInputKind::Press if dance.held => return,
+        KeyInputKind::Press => {
+            dance.count += 1;
+            dance.held = true;
+        }
+        KeyInputKind::Release => dance.held = false,
+    }
+    dance.last = time;
+    let TapDanceAction { actions, threshold } =
+        &recorder_info.tap_dance_map[&(dance.key, dance.condition)];
+    if dance.count == actions.len() {
+        settle_dance(time, recorder_info, recorder_state);
+    } else {
+        fire_waiting_key_with_delay(
+            (KeyInput::press(dance.key), time),
+            tx.clone(),
+            *threshold as u64,
+            &recorder_info.clock,
+        );
+    }
+}
+
+fn fire_waiting_key<T, State: Eq + Copy + Debug + Hash>(
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+) where
+    T: KeyReceiver,
+{
+    if let Some((key, time)) = recorder_state.waiting_key {
+        recorder_state.waiting_key = None;
+        fire_key_input(key, time, recorder_info, recorder_state);
+    }
+}
+
+fn send_key_handler<T, State: Eq + Copy + Debug + Hash>(
+    key: KeyInput,
+    time: SystemTime,
+    recorder_info: &KeyRecorderUnitInfo<State>,
+    recorder_state: &mut KeyRecorderUnitState<T, State>,
+    tx: &Sender<KeyRecorderBehavior>,
+) where
+    T: KeyReceiver,
+{
+    if key.1 == KeyInputKind::Press
+        && recorder_state
+            .held_pairs
+            .iter()
+            .any(|&(a, b)| a == key.0 || b == key.0)
+    {
+        // Repeats of the keys of a held pair.
+        return;
+    }
+    if let Some(dance) = &recorder_state.dance {
+        if key.0 == dance.key {
+            count_tap(key, time, recorder_info, recorder_state, tx);
+            return;
+        }
+        if key.1 == KeyInputKind::Press {
+            settle_dance(time, recorder_info, recorder_state);
+        }
+    }
+    if let Some((waiting_key_kind, waiting_key_time)) = recorder_state.waiting_key {
+        let key_set = [waiting_key_kind, key];
+        match recorder_info.pair_action(waiting_key_kind, key, recorder_state.state) {
+            Some(a)
+                // Keys settled by a timer are stamped with the input which started
+                // the wait, so they may be older than keys passed on meanwhile.
+                if time
+                    .duration_since(waiting_key_time)
+                    .unwrap_or_default()
+                    .as_millis()
+                    <= a.threshold as u128 =>
+            {
+                recorder_state.waiting_key = None;
+                let trigger = key_set
+                    .iter()
+                    .rev()
+                    .find(|k| k.1 == KeyInputKind::Press)
+                    .map(|k| k.0);
+                perform_action(&a.action, trigger, time, recorder_info, recorder_state);
+                if a.hold && key_set.iter().all(|k| k.1 == KeyInputKind::Press) {
+                    recorder_state.held_pairs.push((key_set[0].0, key_set[1].0));
+                }
+                for k in key_set.iter().filter(|k| k.1 == KeyInputKind::Release) {
+                    release_held_by(k.0, time, recorder_info.layer_name, recorder_state);
+                }
+                return;
+            }
+            _ => (),
+        }
+    }
+    fire_waiting_key(recorder_info, recorder_state);
+    if recorder_info.starts_pair(key, recorder_state.state) {
+        recorder_state.waiting_key = Some((key, time));
+        fire_waiting_key_with_delay(
+            (key, time),
+            tx.clone(),
+            recorder_info.threshold as u64,
+            &recorder_info.clock,
+        );
+    } else if key.1 == KeyInputKind::Press
+        && recorder_info
+            .tap_dance_map
+            .contains_key(&(key.0, recorder_state.state))
+    {
+        if recorder_state
+            .held_keys
+            .iter()
+            .any(|(t, _)| *t == Some(key.0))
+        {
+            // Repeats after the count is settled. The output is held already.
+            return;
+        }
+        recorder_state.dance = Some(Dance {
+            key: key.0,
+            condition: recorder_state.state,
+            count: 0,
+            held: false,
+            last: time,
+        });
+        count_tap(key, time, recorder_info, recorder_state, tx);
+    } else {
+        fire_key_input(key, time, recorder_info, recorder_state);
+    }
+}
+
+struct KeyRecorderUnitState<T, State>
+where
+    T: KeyReceiver,
+{
+    key_receiver: T,
+    state: State,
+    waiting_key: Option<(KeyInput, SystemTime)>,
+    dance: Option<Dance<State>>,
+    /// Output keys pressed and not released yet,
+    /// paired with the input key whose press caused them.
+    held_keys: Vec<(Option<Key>, Key)>,
+    /// Input keys of held pairs paired with the key the outputs are held by.
+    /// Releasing the former releases the outputs as well.
+    held_pairs: Vec<(Key, Key)>,
+    /// Disabled layers pass every key unchanged.
+    enabled: bool,
+}
+
+/// Taps of a key of a [`TapDanceEntry`] being counted.
+struct Dance<State> {
+    key: Key,
+    condition: State,
+    count: usize,
+    held: bool,
+    /// Time of the last press or release, which the timer is set for.
+    last: SystemTime,
+}
+
+struct TapDanceAction<State> {
+    /// Actions for one tap, two taps, ...
+    actions: Vec<Action<State>>,
+    threshold: u32,
+}
+
+#[derive(Clone)]
+struct PairAction<State> {
+    action: Action<State>,
+    threshold: u32,
+    hold: bool,
+}
+
+/// An [`Action`] whose output may refer to the key matched by a pattern.
+struct PatternAction<State> {
+    output_keys: Vec<PatternOutput>,
+    transition: State,
+}
+
+impl<State: Copy> PatternAction<State> {
+    fn resolve(&self, matched: Key) -> Action<State> {
+        Action {
+            output_keys: self
+                .output_keys
+                .iter()
+                .map(|o| o.resolve(matched))
+                .collect(),
+            transition: self.transition,
+        }
+    }
+}
+
+struct PairPattern<State> {
+    first: PatternInput,
+    second: KeyInput,
+    action: PatternAction<State>,
+    threshold: u32,
+    hold: bool,
+}
+
+/// A [`RemapLayer`] compiled into tables indexed by the input and the state,
+/// so that finding the entry for a key does not depend on the number of entries.
+struct KeyRecorderUnitInfo<State: Eq + Copy + Debug + Hash> {
+    /// Pairs by their first key, then by the second.
+    pair_hotkeys_map: HashMap<(KeyInput, State), HashMap<KeyInput, PairAction<State>>>,
+    /// The longest threshold of the pairs, for which a key starting any pair waits.
+    threshold: u32,
+    single_hotkeys_map: HashMap<(KeyInput, State), Action<State>>,
+    tap_dance_map: HashMap<(Key, State), TapDanceAction<State>>,
+    /// Single entries with patterns by state, in the order of the config.
+    single_patterns: HashMap<State, Vec<(PatternInput, PatternAction<State>)>>,
+    /// Pairs starting with a pattern by state, in the order of the config.
+    pair_patterns: HashMap<State, Vec<PairPattern<State>>>,
+    layer_name: &'static str,
+    initial_state: State,
+    /// All states appearing in the layer.
+    states: Vec<State>,
+    state_changes: Sender<StateChange>,
+    clock: Clock,
+}
+
+impl<State: Eq + Copy + Debug + Hash> KeyRecorderUnitInfo<State> {
+    /// Build the tables of `key_config`. Entries for the same input and state
+    /// are logged, and the last of them is used.
+    fn compile(key_config: RemapLayer<State>, context: &LayerContext) -> Self {
+        let layer_name = key_config.layer_name;
+        let collision = |kind: &str, input: &dyn Debug, state: State| {
+            log::warn!(
+                "[{layer_name}] more than one {kind} entry for {input:?} in {state:?}. \
+                The last one is used."
+            );
+        };
+        let mut states = vec![key_config.initial_state];
+        for s in key_config
+            .pair_remap_entries
+            .iter()
+            .flat_map(|e| [e.condition, e.transition])
+            .chain(
+                key_config
+                    .single_remap_entries
+                    .iter()
+                    .flat_map(|e| [e.condition, e.transition]),
+            )
+            .chain(key_config.tap_dance_entries.iter().flat_map(|e| {
+                [e.condition]
+                    .into_iter()
+                    .chain(e.taps.iter().map(|(_, transition)| *transition))
+            }))
+            .chain(
+                key_config
+                    .single_pattern_entries
+                    .iter()
+                    .flat_map(|e| [e.condition, e.transition]),
+            )
+            .chain(
+                key_config
+                    .pair_pattern_entries
+                    .iter()
+                    .flat_map(|e| [e.condition, e.transition]),
+            )
+        {
+            if !states.contains(&s) {
+                states.push(s);
+            }
+        }
+        let mut pair_hotkeys_map: HashMap<(KeyInput, State), HashMap<KeyInput, _>> =
+            HashMap::default();
+        for PairRemapEntry {
+            condition,
+            input,
+            output,
+            transition,
+            threshold,
+            hold,
+        } in key_config.pair_remap_entries
+        {
+            let action = PairAction {
+                action: Action {
+                    output_keys: output,
+                    transition,
+                },
+                threshold,
+                hold,
+            };
+            if pair_hotkeys_map
+                .entry((input[0], condition))
+                .or_default()
+                .insert(input[1], action)
+                .is_some()
+            {
+                collision("pair", &input, condition);
+            }
+        }
+        let mut single_hotkeys_map = HashMap::default();
+        for SingleRemapEntry {
+            condition,
+            input,
+            output,
+            transition,
+        } in key_config.single_remap_entries
+        {
+            let action = Action {
+                output_keys: output,
+                transition,
+            };
+            if single_hotkeys_map
+                .insert((input, condition), action)
+                .is_some()
+            {
+                collision("single", &input, condition);
+            }
+        }
+        let mut tap_dance_map = HashMap::default();
+        for TapDanceEntry {
+            condition,
+            input,
+            taps,
+            threshold,
+        } in key_config.tap_dance_entries
+        {
+            let actions = taps
+                .into_iter()
+                .map(|(output_keys, transition)| Action {
+                    output_keys,
+                    transition,
+                })
+                .collect();
+            if tap_dance_map
+                .insert((input, condition), TapDanceAction { actions, threshold })
+                .is_some()
+            {
+                collision("tap dance", &input, condition);
+            }
+        }
+        let mut single_patterns: HashMap<State, Vec<(PatternInput, PatternAction<State>)>> =
+            HashMap::default();
+        for SinglePatternEntry {
+            condition,
+            input,
+            output,
+            transition,
+        } in key_config.single_pattern_entries
+        {
+            let patterns = single_patterns.entry(condition).or_default();
+            if patterns.iter().any(|(p, _)| *p == input) {
+                log::warn!(
+                    "[{layer_name}] more than one single pattern entry for {input:?} \
+                    in {condition:?}. The first one is used."
+                );
+            }
+            patterns.push((
+                input,
+                PatternAction {
+                    output_keys: output,
+                    transition,
+                },
+            ));
+        }
+        let mut pair_patterns: HashMap<State, Vec<PairPattern<State>>> = HashMap::default();
+        for PairPatternEntry {
+            condition,
+            input: (first, second),
+            output,
+            transition,
+            threshold,
+            hold,
+        } in key_config.pair_pattern_entries
+        {
+            let patterns = pair_patterns.entry(condition).or_default();
+            if patterns
+                .iter()
+                .any(|p| p.first == first && p.second == second)
+            {
+                log::warn!(
+                    "[{layer_name}] more than one pair pattern entry for \
+                    {:?} in {condition:?}. The first one is used.",
+                    (&first, second)
+                );
+            }
+            patterns.push(PairPattern {
+                first,
+                second,
+                action: PatternAction {
+                    output_keys: output,
+                    transition,
+                },
+                threshold,
+                hold,
+            });
+        }
+        // A press starting a pair is never counted as a tap or remapped alone,
+        // and a tapped key is never remapped alone.
+        for &(key, state) in tap_dance_map.keys() {
+            if pair_hotkeys_map.contains_key(&(KeyInput::press(key), state)) {
+                log::warn!(
+                    "[{layer_name}] the tap dance of {key:?} in {state:?} \
+                    is shadowed by a pair starting with it"
+                );
+            }
+            if single_hotkeys_map.contains_key(&(KeyInput::press(key), state)) {
+                log::warn!(
+                    "[{layer_name}] the single entry for {:?} in {state:?} \
+                    is shadowed by a tap dance",
+                    KeyInput::press(key)
+                );
+            }
+        }
+        let threshold = pair_hotkeys_map
+            .values()
+            .flat_map(|seconds| seconds.values().map(|a| a.threshold))
+            .chain(pair_patterns.values().flatten().map(|p| p.threshold))
+            .max()
+            .unwrap_or(0);
+        log::debug!("threshold of {layer_name} = {threshold}");
+        KeyRecorderUnitInfo {
+            pair_hotkeys_map,
+            threshold,
+            single_hotkeys_map,
+            tap_dance_map,
+            single_patterns,
+            pair_patterns,
+            layer_name,
+            initial_state: key_config.initial_state,
+            states,
+            state_changes: context.state_changes.clone(),
+            clock: context.clock.clone(),
+        }
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash> KeyRecorderUnitInfo<State> {
+    /// The action for `key` alone in `state`.
+    fn single_action(&self, key: KeyInput, state: State) -> Option<Cow<'_, Action<State>>> {
+        if let Some(action) = self.single_hotkeys_map.get(&(key, state)) {
+            return Some(Cow::Borrowed(action));
+        }
+        self.single_patterns
+            .get(&state)?
+            .iter()
+            .find(|(p, _)| p.matches(key))
+            .map(|(_, a)| Cow::Owned(a.resolve(key.0)))
+    }
+
+    /// The action for `first` followed by `second` in `state`.
+    fn pair_action(
+        &self,
+        first: KeyInput,
+        second: KeyInput,
+        state: State,
+    ) -> Option<Cow<'_, PairAction<State>>> {
+        if let Some(action) = self
+            .pair_hotkeys_map
+            .get(&(first, state))
+            .and_then(|seconds| seconds.get(&second))
+        {
+            return Some(Cow::Borrowed(action));
+        }
+        self.pair_patterns
+            .get(&state)?
+            .iter()
+            .find(|p| p.second == second && p.first.matches(first))
+            .map(|p| {
+                Cow::Owned(PairAction {
+                    action: p.action.resolve(first.0),
+                    threshold: p.threshold,
+                    hold: p.hold,
+                })
+            })
+    }
+
+    /// Whether `key` starts a pair in `state` and waits for the second key.
+    fn starts_pair(&self, key: KeyInput, state: State) -> bool {
+        self.pair_hotkeys_map.contains_key(&(key, state))
+            || self
+                .pair_patterns
+                .get(&state)
+                .is_some_and(|patterns| patterns.iter().any(|p| p.first.matches(key)))
+    }
+}
+
+impl KeyRecorder {
+    /// Run a layer other than a [`RemapLayer`] on its own thread.
+    pub(crate) fn spawn(
+        layer_name: &'static str,
+        layer: impl FnOnce(Sender<KeyRecorderBehavior>, Inbox) + Send + 'static,
+    ) -> KeyRecorder {
+        let (tx, rx) = channel();
+        let tx_clone = tx.clone();
+        thread::spawn(move || layer(tx_clone, Inbox::new(rx)));
+        KeyRecorder { tx, layer_name }
+    }
+
+    pub(crate) fn new<State: Eq + Copy + Debug + Hash + Send + 'static>(
+        key_config: RemapLayer<State>,
+        key_receiver: impl KeyReceiver + 'static,
+        context: &LayerContext,
+    ) -> KeyRecorder {
+        let (tx, rx) = channel();
+        let tx_clone = tx.clone();
+        let layer_name = key_config.layer_name;
+        let initial_state = key_config.initial_state;
+        let recorder_info = KeyRecorderUnitInfo::compile(key_config, context);
+        thread::spawn(move || {
+            let mut recorder_state = KeyRecorderUnitState {
+                key_receiver,
+                state: initial_state,
+                waiting_key: None,
+                dance: None,
+                held_keys: Vec::new(),
+                held_pairs: Vec::new(),
+                enabled: true,
+            };
+            let _ = recorder_info.state_changes.send(StateChange {
+                layer: layer_name,
+                previous: None,
+                state: format!("{initial_state:?}"),
+            });
+            for received in Inbox::new(rx) {
+                match received {
+                    KeyRecorderBehavior::FireSpecificWaitingKey((key, time), _) => {
+                        fire_specific_waiting_key_handler(
+                            key,
+                            time,
+                            &recorder_info,
+                            &mut recorder_state,
+                        )
+                    }
+                    KeyRecorderBehavior::SendKey((key, time)) if !recorder_state.enabled => {
+                        recorder_state.key_receiver.send_key(key, time)
+                    }
+                    KeyRecorderBehavior::SendKey((key, time)) => {
+                        send_key_handler(key, time, &recorder_info, &mut recorder_state, &tx_clone)
+                    }
+                    KeyRecorderBehavior::Reset => {
+                        reset_handler(&recorder_info, &mut recorder_state)
+                    }
+                    KeyRecorderBehavior::Stop => {
+                        reset_layer(&recorder_info, &mut recorder_state);
+                        recorder_state.key_receiver.stop();
+                        break;
+                    }
+                    KeyRecorderBehavior::Control(message) => {
+                        control_handler(message, &recorder_info, &mut recorder_state)
+                    }
+                }
+            }
+        });
+        KeyRecorder { tx, layer_name }
+    }
+}
+
+impl KeyReceiver for KeyRecorder {
+    fn send_key(&mut self, key: KeyInput, time: SystemTime) {
+        log::debug!("[{}] {:?}", self.layer_name, key);
+        self.tx
+            .send(KeyRecorderBehavior::SendKey((key, time)))
+            .unwrap();
+    }
+
+    fn reset(&mut self) {
+        log::debug!("[{}] reset", self.layer_name);
+        self.tx.send(KeyRecorderBehavior::Reset).unwrap();
+    }
+
+    fn stop(&mut self) {
+        log::debug!("[{}] stop", self.layer_name);
+        self.tx.send(KeyRecorderBehavior::Stop).unwrap();
+    }
+
+    fn control(&mut self, message: LayerControl) {
+        self.tx.send(KeyRecorderBehavior::Control(message)).unwrap();
+    }
+}
+
+impl KeyReceiver for KeyWriterHandle {
+    fn send_key(&mut self, key: KeyInput, time: SystemTime) {
+        self.fire_key_input(key, Some(time));
+    }
+
+    fn reset(&mut self) {
+        self.release_all();
+    }
+
+    fn stop(&mut self) {
+        self.release_all();
+    }
+
+    fn control(&mut self, message: LayerControl) {
+        match message {
+            LayerControl::Query(statuses, reply) => {
+                let _ = reply.send(statuses);
+            }
+            LayerControl::SetState { layer, reply, .. }
+            | LayerControl::SetEnabled { layer, reply, .. } => {
+                let _ = reply.send(Err(format!("no layer named {layer:?}")));
+            }
+        }
+    }
+}
+
+/// The emergency stop chord used unless another one is configured.
+/// Desktops bind shortcuts like Ctrl+Alt+Esc, but not both Ctrl keys at once.
+pub const DEFAULT_EMERGENCY_STOP_CHORD: [Key; 3] =
+    [Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL, Key::KEY_BACKSPACE];
+
+#[derive(Debug)]
+pub struct KeyConfig<L> {
+    pub(crate) layers: L,
+    pub(crate) emergency_stop_chord: Vec<Key>,
+    pub(crate) release_all_chord: Vec<Key>,
+    pub(crate) pause_chord: Vec<Key>,
+    pub(crate) control_socket: Option<PathBuf>,
+    pub(crate) state_hooks: Vec<StateHook>,
+    pub(crate) debounce: Option<DebounceConfig>,
+    pub(crate) macros: Option<MacroConfig>,
+    pub(crate) grab_mice: bool,
+    pub(crate) run_as: Option<String>,
+    pub(crate) device_groups: Vec<Vec<String>>,
+}
+
+pub struct RemapLayers<State: Eq + Copy + Debug + Hash + 'static, Tail>(RemapLayer<State>, Tail);
+
+/// Layers whose number is known only at run time, like the ones loaded from a file.
+pub struct RemapLayerVec<State: Eq + Copy + Debug + Hash + 'static, Tail>(
+    Vec<RemapLayer<State>>,
+    Tail,
+);
+
+/// What the layers of a stack share.
+pub struct LayerContext {
+    pub(crate) writer: SharedKeyWriter,
+    pub(crate) state_changes: Sender<StateChange>,
+    pub(crate) clock: Clock,
+    /// Whether a [`CommandLayer`](crate::CommandLayer) runs its commands,
+    /// which a simulator only logs.
+    pub(crate) run_commands: bool,
+}
+
+pub trait ToKeyRecorder {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder;
+}
+
+impl<State: Eq + Copy + Debug + Hash + Send + 'static, T: ToKeyRecorder> ToKeyRecorder
+    for RemapLayers<State, T>
+{
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        KeyRecorder::new(self.0.clone(), self.1.to_key_recorder(context), context)
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash + Send + 'static> ToKeyRecorder for RemapLayer<State> {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        KeyRecorder::new(self.clone(), context.writer.handle("layers"), context)
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash + Send + 'static, T: ToKeyRecorder> ToKeyRecorder
+    for RemapLayerVec<State, T>
+{
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        self.0
+            .iter()
+            .rev()
+            .fold(self.1.to_key_recorder(context), |tail, layer| {
+                KeyRecorder::new(layer.clone(), tail, context)
+            })
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash + Send + 'static> ToKeyRecorder for Vec<RemapLayer<State>> {
+    fn to_key_recorder(&self, context: &LayerContext) -> KeyRecorder {
+        match self.split_last() {
+            Some((last, init)) => {
+                RemapLayerVec(init.to_vec(), last.clone()).to_key_recorder(context)
+            }
+            None => RemapLayer::default().to_key_recorder(context),
+        }
+    }
+}
+
+pub trait AddLayer {
+    type LayerAdded<A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T>;
+}
+
+impl<State: Eq + Copy + Debug + Hash + 'static, Tail: AddLayer> AddLayer
+    for RemapLayers<State, Tail>
+{
+    type LayerAdded<A> = RemapLayers<State, Tail::LayerAdded<A>>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        RemapLayers(self.0, self.1.add_layer(tail))
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash + 'static> AddLayer for RemapLayer<State> {
+    type LayerAdded<A> = RemapLayers<State, A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        RemapLayers(self, tail)
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash + 'static, Tail: AddLayer> AddLayer
+    for RemapLayerVec<State, Tail>
+{
+    type LayerAdded<A> = RemapLayerVec<State, Tail::LayerAdded<A>>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        RemapLayerVec(self.0, self.1.add_layer(tail))
+    }
+}
+
+impl<State: Eq + Copy + Debug + Hash + 'static> AddLayer for Vec<RemapLayer<State>> {
+    type LayerAdded<A> = RemapLayerVec<State, A>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        RemapLayerVec(self, tail)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EmptyLayer;
+
+impl AddLayer for EmptyLayer {
+    type LayerAdded<A> = A;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        tail
+    }
+}
+
+impl Default for KeyConfig<EmptyLayer> {
+    fn default() -> Self {
+        Self {
+            layers: Default::default(),
+            emergency_stop_chord: DEFAULT_EMERGENCY_STOP_CHORD.to_vec(),
+            release_all_chord: Default::default(),
+            pause_chord: Default::default(),
+            control_socket: Default::default(),
+            state_hooks: Default::default(),
+            debounce: Default::default(),
+            macros: Default::default(),
+            grab_mice: Default::default(),
+            run_as: Default::default(),
+            device_groups: Default::default(),
+        }
+    }
+}
+
+impl<A: AddLayer> AddLayer for KeyConfig<A> {
+    type LayerAdded<B> = KeyConfig<A::LayerAdded<B>>;
+
+    fn add_layer<T: AddLayer>(self, tail: T) -> Self::LayerAdded<T> {
+        KeyConfig {
+            layers: self.layers.add_layer(tail),
+            emergency_stop_chord: self.emergency_stop_chord,
+            release_all_chord: self.release_all_chord,
+            pause_chord: self.pause_chord,
+            control_socket: self.control_socket,
+            state_hooks: self.state_hooks,
+            debounce: self.debounce,
+            macros: self.macros,
+            grab_mice: self.grab_mice,
+            run_as: self.run_as,
+            device_groups: self.device_groups,
+        }
+    }
+}
+
+impl<A> KeyConfig<A> {
+    /// Change the chord which ungrabs the keyboards and exits.
+    /// The chord is checked before the debouncer and the layers see the keys,
+    /// so neither can delay or override it. It is validated when the config runs,
+    /// and must neither contain nor be a part of another chord.
+    pub fn emergency_stop_chord(mut self, keys: &[Key]) -> Self {
+        self.emergency_stop_chord = keys.to_vec();
+        self
+    }
+    /// Pressing all of `keys` at once releases every key held by the virtual
+    /// keyboard and puts every layer back to its `initial_state`.
+    pub fn release_all_chord(mut self, keys: &[Key]) -> Self {
+        if keys.is_empty() {
+            eprintln!("release all chord must not be empty");
+            std::process::exit(1);
+        }
+        self.release_all_chord = keys.to_vec();
+        self
+    }
+    /// Pressing all of `keys` at once toggles pausing. While paused, keys are
+    /// sent to the virtual keyboard unchanged, bypassing all layers.
+    pub fn pause_chord(mut self, keys: &[Key]) -> Self {
+        if keys.is_empty() {
+            eprintln!("pause chord must not be empty");
+            std::process::exit(1);
+        }
+        self.pause_chord = keys.to_vec();
+        self
+    }
+
+    /// Listen for commands on a unix domain socket at `path`.
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control_socket = Some(path.into());
+        self
+    }
+    /// Run `hook` whenever the state of a layer changes.
+    pub fn state_hook(mut self, hook: StateHook) -> Self {
+        self.state_hooks.push(hook);
+        self
+    }
+    /// Suppress chattering before the keys reach any layer.
+    pub fn debounce(mut self, config: DebounceConfig) -> Self {
+        self.debounce = Some(config);
+        self
+    }
+
+    /// Record the keys sent to the virtual keyboard and play them back.
+    /// The chords are checked before any layer sees the keys.
+    pub fn macros(mut self, config: MacroConfig) -> Self {
+        if config.record_chord.is_empty() {
+            eprintln!("macro record chord must not be empty");
+            std::process::exit(1);
+        }
+        self.macros = Some(config);
+        self
+    }
+
+    /// Also grab mice. Layers receive their buttons like `BTN_SIDE`, and
+    /// wheel clicks as presses of `WHEEL_UP`, `WHEEL_DOWN`, `WHEEL_LEFT`
+    /// and `WHEEL_RIGHT`. Their movement is passed on as it is.
+    pub fn grab_mice(mut self) -> Self {
+        self.grab_mice = true;
+        self
+    }
+
+    /// Switch to `user` once the devices, the virtual devices and the control
+    /// socket are open, so that layers, hooks and commands do not run as root.
+    /// The control socket is given to `user`, and so are the missing
+    /// directories of the state files and the macro file.
+    /// The keys are remapped in a forked process, and this one stays root
+    /// to grab devices plugged in later and to start it again on `reload`.
+    pub fn run_as(mut self, user: impl Into<String>) -> Self {
+        self.run_as = Some(user.into());
+        self
+    }
+
+    /// Send the keys of the devices whose names contain one of `names`
+    /// through one shared layer stack, e.g. both halves of a split keyboard,
+    /// so that pairs and modifiers work across them. `""` matches every device.
+    /// Other devices get a stack each. Call it again for another group.
+    pub fn merge_devices(mut self, names: &[&str]) -> Self {
+        if names.is_empty() {
+            eprintln!("device group must not be empty");
+            std::process::exit(1);
+        }
+        self.device_groups
+            .push(names.iter().map(|n| n.to_string()).collect());
+        self
+    }
+}
@@ -0,0 +1,250 @@
+use crate::clock::Clock;
+use crate::debounce::{DebounceConfig, Debouncer};
+use crate::read_keys::{KeyReceiver, KeyRecorder, LayerContext, LayerControl, ToKeyRecorder};
+use crate::write_keys::{KeyWriter, SharedKeyWriter};
+use crate::{completes_chord, get_keyboard_devices, read_devices, Event, KeyInput};
+use evdev::{EventType, InputEvent, InputEventKind, Key};
+use rustc_hash::FxHashSet as HashSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long the clock runs after the last event of a trace, for the timeouts to pass.
+const SETTLE: Duration = Duration::from_secs(10);
+
+/// Runs layers on recorded key events instead of keyboards, and collects the keys
+/// they send instead of writing them.
+///
+/// The layers see the timestamps of the events as on a real keyboard, and pair
+/// thresholds and timeouts pass on a simulated clock following the timestamps,
+/// so a trace runs as fast as the layers can handle it.
+pub struct Simulator {
+    key_recorder: KeyRecorder,
+    writer: SharedKeyWriter,
+    debounce: Option<DebounceConfig>,
+    clock: Clock,
+}
+
+impl Simulator {
+    pub(crate) fn new(layers: &impl ToKeyRecorder, debounce: Option<DebounceConfig>) -> Simulator {
+        let clock = Clock::simulated();
+        let writer = SharedKeyWriter::new(KeyWriter::simulated());
+        let (state_changes, _) = channel();
+        let key_recorder = layers.to_key_recorder(&LayerContext {
+            writer: writer.clone(),
+            state_changes,
+            clock: clock.clone(),
+            run_commands: false,
+        });
+        Simulator {
+            key_recorder,
+            writer,
+            debounce,
+            clock,
+        }
+    }
+
+    /// Send the key events of `trace` to the layers and return the keys they send.
+    /// The output only depends on the trace and the previous replays.
+    /// The trace is shifted to start after the previous one ended.
+    /// Events are sent in the order of the trace, and an event stamped before the one
+    /// before it is sent with the time of that one, like the keys of merged devices.
+    pub fn replay(&mut self, trace: &[InputEvent]) -> Vec<KeyInput> {
+        let Some(first) = trace.first() else {
+            return Vec::new();
+        };
+        let start = self.clock.now() + SETTLE;
+        let shift =
+            |time: SystemTime| start + time.duration_since(first.timestamp()).unwrap_or_default();
+        let events = match &self.debounce {
+            Some(config) => debounce(config, trace),
+            None => trace.iter().map(|e| (e.timestamp(), *e)).collect(),
+        };
+        let mut last = start;
+        for (arrival, e) in events {
+            if let InputEventKind::Key(key) = e.kind() {
+                self.advance(shift(arrival));
+                // Debounced keys arrive after their timestamps, which do not go back.
+                last = last.max(shift(e.timestamp()));
+                self.key_recorder
+                    .send_key(KeyInput(key, e.value().into()), last);
+            }
+        }
+        self.advance(shift(trace.last().unwrap().timestamp()) + SETTLE);
+        self.writer.take_simulated()
+    }
+
+    /// Release the keys held by the layers and put them back in their initial states.
+    pub fn reset(&mut self) {
+        self.key_recorder.reset();
+        self.wait_for_layers();
+        self.writer.take_simulated();
+    }
+
+    /// Run the timers due before `time` in order, letting the layers handle the keys
+    /// sent before and what each timer causes before running the next one.
+    /// Timers due at `time` run after the keys stamped `time`, as a layer handles
+    /// keys stamped at the deadline of a timeout before the timeout.
+    fn advance(&mut self, time: SystemTime) {
+        loop {
+            // Layers handling keys may set timers which are due at once.
+            let fired = self.clock.fired();
+            self.wait_for_layers();
+            if self.clock.fired() != fired {
+                continue;
+            }
+            match self.clock.next_deadline() {
+                Some(deadline) if deadline < time => self.clock.advance(deadline),
+                _ => break,
+            }
+        }
+    }
+
+    /// Wait until the layers have handled everything sent to them before.
+    fn wait_for_layers(&mut self) {
+        let (tx, rx) = channel();
+        self.key_recorder
+            .control(LayerControl::Query(Vec::new(), tx));
+        let _ = rx.recv();
+    }
+}
+
+/// The events of `trace` passed by a debouncer of `config`,
+/// with the times they are passed on, which are after their timestamps if deferred.
+fn debounce(config: &DebounceConfig, trace: &[InputEvent]) -> Vec<(SystemTime, InputEvent)> {
+    let (tx, rx) = channel();
+    let mut debouncer = Debouncer::new(config.clone(), tx);
+    let mut events = Vec::new();
+    let mut passed = |time: SystemTime| {
+        events.extend(rx.try_iter().filter_map(|event| match event {
+            Event::Input(_, e) => Some((time, e)),
+            _ => None,
+        }))
+    };
+    for e in trace {
+        while let Some(deadline) = debouncer.next_deadline().filter(|d| *d <= e.timestamp()) {
+            debouncer.expire(deadline);
+            passed(deadline);
+        }
+        debouncer.receive(0, *e);
+        passed(e.timestamp());
+    }
+    while let Some(deadline) = debouncer.next_deadline() {
+        debouncer.expire(deadline);
+        passed(deadline);
+    }
+    events
+}
+
+/// Parse a trace of key events, one per line: the timestamp in seconds,
+/// the key and the value, e.g. `1.234567 KEY_A 1`. Lines starting with `#` are ignored.
+pub fn parse_trace(s: &str) -> Result<Vec<InputEvent>, String> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'))
+        .map(|(i, l)| parse_event(l).map_err(|e| format!("line {}: {e}", i + 1)))
+        .collect()
+}
+
+pub fn load_trace(path: &Path) -> Result<Vec<InputEvent>, String> {
+    let s = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    parse_trace(&s).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn parse_event(line: &str) -> Result<InputEvent, String> {
+    let [time, key, value] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(format!(
+            "expected \"<seconds> <key> <value>\", found {line:?}"
+        ));
+    };
+    let (sec, usec) = time.split_once('.').unwrap_or((time, "0"));
+    let invalid_time = |_| format!("invalid time {time:?}");
+    let sec = sec.parse::<i64>().map_err(invalid_time)?;
+    let usec = format!("{usec:0<6}")
+        .parse::<i64>()
+        .ok()
+        .filter(|u| *u < 1_000_000)
+        .ok_or_else(|| format!("invalid time {time:?}"))?;
+    let key = Key::from_str(key).map_err(|_| format!("unknown key {key:?}"))?;
+    let value = value
+        .parse::<i32>()
+        .ok()
+        .filter(|v| (0..=2).contains(v))
+        .ok_or_else(|| format!("invalid value {value:?}"))?;
+    Ok(InputEvent::from(libc::input_event {
+        time: libc::timeval {
+            tv_sec: sec,
+            tv_usec: usec,
+        },
+        type_: EventType::KEY.0,
+        code: key.code(),
+        value,
+    }))
+}
+
+fn write_event(trace: &mut String, t: Duration, key: Key, value: i32) {
+    let _ = writeln!(
+        trace,
+        "{}.{:06} {key:?} {value}",
+        t.as_secs(),
+        t.subsec_micros()
+    );
+}
+
+/// Watch the keyboards without grabbing them and write their key events to `path`
+/// as a trace for [`Simulator::replay`].
+/// Runs for `duration` or until the default emergency stop chord is pressed.
+/// The remapper must not be running, since it grabs the keyboards.
+pub fn record_trace(path: &Path, duration: Duration) {
+    let keyboards = get_keyboard_devices().map(|(_, d)| d).collect::<Vec<_>>();
+    if keyboards.is_empty() {
+        eprintln!("Keyboard not found");
+        std::process::exit(1);
+    }
+    let stop_chord = crate::DEFAULT_EMERGENCY_STOP_CHORD;
+    println!(
+        "Recording for {} seconds. Press {stop_chord:?} to finish early.",
+        duration.as_secs()
+    );
+    let (tx, rx) = channel();
+    read_devices(keyboards.into_iter(), tx);
+    let end = Instant::now() + duration;
+    let mut trace = String::new();
+    let mut first = None;
+    let mut pressed_keys = HashSet::default();
+    loop {
+        let input_event = match rx.recv_timeout(end.saturating_duration_since(Instant::now())) {
+            Ok(Event::Input(_, input_event)) => input_event,
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+        };
+        let InputEventKind::Key(key) = input_event.kind() else {
+            continue;
+        };
+        let time = input_event.timestamp();
+        let t = time
+            .duration_since(*first.get_or_insert(time))
+            .unwrap_or_default();
+        if input_event.value() == 0 {
+            pressed_keys.remove(&key);
+        } else {
+            pressed_keys.insert(key);
+        }
+        if input_event.value() == 1 && completes_chord(&stop_chord, key, &pressed_keys) {
+            // End with nothing held, leaving out the last key of the chord.
+            pressed_keys.remove(&key);
+            for k in pressed_keys {
+                write_event(&mut trace, t, k, 0);
+            }
+            break;
+        }
+        write_event(&mut trace, t, key, input_event.value());
+    }
+    if let Err(e) = fs::write(path, trace) {
+        eprintln!("{}: {e}", path.display());
+        std::process::exit(1);
+    }
+}
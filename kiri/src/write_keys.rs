@@ -0,0 +1,241 @@
+use crate::clock;
+use crate::latency::Latencies;
+use crate::pointer::{is_pointer_key, Pointer};
+use crate::read_keys::{KeyInput, KeyInputKind};
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{EventType, InputEvent, Key};
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+pub(crate) const KEYBOARD_NAME: &str = "kiri virtual keyboard";
+
+/// Where a [`KeyWriter`] writes the keys.
+enum Output {
+    Devices {
+        keyboard: VirtualDevice,
+        /// Receives mouse buttons and the keys moving the pointer.
+        pointer: Pointer,
+    },
+    /// Keys kept for a [`Simulator`](crate::Simulator) instead of being written.
+    Simulated(Vec<KeyInput>),
+}
+
+pub struct KeyWriter {
+    output: Output,
+    /// Keys pressed on the virtual keyboard and the number of handles
+    /// holding each of them.
+    pressed_keys: HashMap<Key, usize>,
+    /// Receivers of the emitted keys.
+    subscribers: Vec<Sender<String>>,
+    /// Keys emitted since recording a macro started.
+    recording: Option<Vec<(Instant, KeyInput)>>,
+    latencies: Latencies,
+}
+
+impl KeyWriter {
+    pub fn new() -> Result<KeyWriter, io::Error> {
+        let mut key_set = evdev::AttributeSet::<evdev::Key>::new();
+        evdev_keys::all_keys().for_each(|key| {
+            key_set.insert(key);
+        });
+        Ok(KeyWriter::with_output(Output::Devices {
+            keyboard: VirtualDeviceBuilder::new()?
+                .name(KEYBOARD_NAME.as_bytes())
+                .with_keys(&key_set)
+                .unwrap()
+                .build()
+                .unwrap(),
+            pointer: Pointer::new()?,
+        }))
+    }
+
+    /// A writer without devices, which keeps the keys for [`Self::take_simulated`].
+    pub(crate) fn simulated() -> KeyWriter {
+        KeyWriter::with_output(Output::Simulated(Vec::new()))
+    }
+
+    fn with_output(output: Output) -> KeyWriter {
+        KeyWriter {
+            output,
+            pressed_keys: HashMap::default(),
+            subscribers: Vec::new(),
+            recording: None,
+            latencies: Latencies::default(),
+        }
+    }
+
+    fn press(&mut self, key: Key, newly_held: bool, time: Option<SystemTime>) {
+        if newly_held {
+            *self.pressed_keys.entry(key).or_insert(0) += 1;
+        }
+        self.fire_key_input(KeyInput::press(key), time);
+    }
+
+    fn release(&mut self, key: Key, time: Option<SystemTime>) {
+        if let Some(n) = self.pressed_keys.get_mut(&key) {
+            *n -= 1;
+            if *n == 0 {
+                self.pressed_keys.remove(&key);
+                self.fire_key_input(KeyInput::release(key), time);
+            }
+        }
+    }
+
+    /// Release all keys pressed on the virtual keyboard.
+    pub fn release_all(&mut self) {
+        let pressed_keys = std::mem::take(&mut self.pressed_keys);
+        for key in pressed_keys.into_keys() {
+            self.fire_key_input(KeyInput::release(key), None);
+        }
+    }
+
+    /// Write `key`. `time` is the timestamp of the input event which caused it,
+    /// if any, from which the latency is measured.
+    pub fn fire_key_input(&mut self, key: KeyInput, time: Option<SystemTime>) {
+        log::debug!("-----> {:?}", key);
+        match &mut self.output {
+            Output::Devices { pointer, .. } if is_pointer_key(key.0) => {
+                pointer.send(key.0, key.1 == KeyInputKind::Press);
+            }
+            Output::Devices { keyboard, .. } => {
+                let msg = [InputEvent::new(EventType::KEY, key.0.code(), key.1.into())];
+                keyboard.emit(&msg).unwrap();
+            }
+            Output::Simulated(keys) => {
+                keys.push(key);
+                return;
+            }
+        }
+        if let Some(time) = time {
+            self.latencies
+                .record(clock::now().duration_since(time).unwrap_or_default());
+        }
+        if !self.subscribers.is_empty() {
+            let line = format!(
+                "{:?} {}",
+                key.0,
+                match key.1 {
+                    KeyInputKind::Press => "press",
+                    KeyInputKind::Release => "release",
+                }
+            );
+            self.subscribers.retain(|s| s.send(line.clone()).is_ok());
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.push((Instant::now(), key));
+        }
+        std::thread::sleep(core::time::Duration::from_millis(5));
+    }
+}
+
+impl Drop for KeyWriter {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+/// A [`KeyWriter`] shared by several senders of keys.
+#[derive(Clone)]
+pub struct SharedKeyWriter(Arc<Mutex<KeyWriter>>);
+
+impl SharedKeyWriter {
+    pub fn new(writer: KeyWriter) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Receive a line for each key emitted from now on.
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (tx, rx) = channel();
+        self.0.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    /// Start recording the emitted keys, discarding an unfinished recording.
+    pub fn start_recording(&self) {
+        self.0.lock().unwrap().recording = Some(Vec::new());
+    }
+
+    /// Stop recording and take the keys emitted since [`Self::start_recording`].
+    pub fn stop_recording(&self) -> Option<Vec<(Instant, KeyInput)>> {
+        self.0.lock().unwrap().recording.take()
+    }
+
+    /// Pass on the movement of a grabbed mouse to the virtual pointer.
+    pub fn move_pointer(&self, events: Vec<InputEvent>) {
+        if let Output::Devices { pointer, .. } = &self.0.lock().unwrap().output {
+            pointer.send_motion(events);
+        }
+    }
+
+    /// The histogram of latencies, cleared if `reset`.
+    pub(crate) fn latencies(&self, reset: bool) -> Vec<String> {
+        let mut writer = self.0.lock().unwrap();
+        let report = writer.latencies.report();
+        if reset {
+            writer.latencies = Latencies::default();
+        }
+        report
+    }
+
+    /// Take the keys written so far by a [`KeyWriter::simulated`] writer.
+    pub(crate) fn take_simulated(&self) -> Vec<KeyInput> {
+        match &mut self.0.lock().unwrap().output {
+            Output::Simulated(keys) => std::mem::take(keys),
+            Output::Devices { .. } => Vec::new(),
+        }
+    }
+
+    pub fn handle(&self, name: &'static str) -> KeyWriterHandle {
+        KeyWriterHandle {
+            writer: self.clone(),
+            name,
+            pressed_keys: HashSet::default(),
+        }
+    }
+}
+
+/// A sender of keys to a [`SharedKeyWriter`].
+/// It keeps track of the keys it pressed so that it only releases its own keys
+/// and releases all of them when it is reset or dropped.
+pub struct KeyWriterHandle {
+    writer: SharedKeyWriter,
+    name: &'static str,
+    pressed_keys: HashSet<Key>,
+}
+
+impl KeyWriterHandle {
+    /// Write `key`. `time` is as in [`KeyWriter::fire_key_input`].
+    pub fn fire_key_input(&mut self, key: KeyInput, time: Option<SystemTime>) {
+        let mut writer = self.writer.0.lock().unwrap();
+        match key.1 {
+            KeyInputKind::Press => {
+                let newly_held = self.pressed_keys.insert(key.0);
+                writer.press(key.0, newly_held, time);
+            }
+            KeyInputKind::Release => {
+                if self.pressed_keys.remove(&key.0) {
+                    writer.release(key.0, time);
+                } else {
+                    log::debug!("[{}] ignored {:?}, not pressed", self.name, key);
+                }
+            }
+        }
+    }
+
+    /// Release all keys pressed through this handle.
+    pub fn release_all(&mut self) {
+        let mut writer = self.writer.0.lock().unwrap();
+        for key in self.pressed_keys.drain() {
+            writer.release(key, None);
+        }
+    }
+}
+
+impl Drop for KeyWriterHandle {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
@@ -0,0 +1,73 @@
+use kiri::{chattering_report, parse_trace};
+
+#[test]
+fn presses_soon_after_releases_are_counted_as_chattering() {
+    let report = chattering_report(
+        &parse_trace(
+            "0.000 KEY_B 1
+            0.020 KEY_B 0
+            0.220 KEY_B 1
+            0.240 KEY_B 0
+            1.000 KEY_A 1
+            1.050 KEY_A 0
+            1.053 KEY_A 1
+            1.100 KEY_A 0
+            1.112 KEY_A 1
+            1.150 KEY_A 0
+            1.400 KEY_A 1
+            1.450 KEY_A 0",
+        )
+        .unwrap(),
+    );
+    let lines = report.lines().collect::<Vec<_>>();
+    // The key chattering most comes first.
+    assert_eq!(lines[0], "KEY_A: 4 presses, 2 too fast");
+    assert_eq!(lines[1].split_whitespace().last(), Some("1"), "0-5 ms");
+    assert_eq!(lines[2].split_whitespace().last(), Some("0"), "5-10 ms");
+    assert_eq!(lines[3].split_whitespace().last(), Some("1"), "10-20 ms");
+    assert_eq!(lines[7].split_whitespace().last(), Some("1"), "100- ms");
+    assert_eq!(lines[8], "KEY_B: 2 presses, 0 too fast");
+    // Rounded up from 12 ms with some margin.
+    assert_eq!(
+        lines[16..],
+        [
+            "Suggested debounce windows:",
+            "    per_key: vec![(KEY_A, 20)],"
+        ]
+    );
+}
+
+#[test]
+fn suggested_windows_stay_under_the_chattering_threshold() {
+    let report = chattering_report(
+        &parse_trace(
+            "0.000 KEY_A 1
+            0.050 KEY_A 0
+            0.078 KEY_A 1
+            0.100 KEY_A 0",
+        )
+        .unwrap(),
+    );
+    assert!(
+        report.ends_with("per_key: vec![(KEY_A, 30)],\n"),
+        "{report}"
+    );
+}
+
+#[test]
+fn slow_typing_is_not_chattering() {
+    let report = chattering_report(
+        &parse_trace(
+            "0.000 KEY_A 1
+            0.050 KEY_A 0
+            0.200 KEY_A 1
+            0.250 KEY_A 0",
+        )
+        .unwrap(),
+    );
+    assert!(
+        report.starts_with("KEY_A: 2 presses, 0 too fast\n"),
+        "{report}"
+    );
+    assert!(report.ends_with("No chattering found.\n"), "{report}");
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::{ctrl, tap};
+use kiri::evdev_keys::*;
+use kiri::{AddLayer, KeyConfig, KeyInput, PairRemapEntry, RemapLayer};
+
+/// A and ; held together work as Ctrl.
+fn replay(trace: &str) -> Vec<KeyInput> {
+    let layer = RemapLayer {
+        pair_remap_entries: PairRemapEntry {
+            condition: (),
+            input: [KeyInput::press(KEY_A), KeyInput::press(KEY_SEMICOLON)],
+            output: vec![KeyInput::press(KEY_LEFTCTRL)],
+            transition: (),
+            threshold: 50,
+            hold: true,
+        }
+        .order_insensitive()
+        .collect(),
+        ..Default::default()
+    };
+    common::replay(KeyConfig::default().add_layer(layer), trace)
+}
+
+#[test]
+fn the_output_is_held_while_both_keys_are_held() {
+    let keys = replay(
+        "0.000 KEY_SEMICOLON 1
+        0.020 KEY_A 1
+        0.100 KEY_C 1
+        0.150 KEY_C 0
+        0.200 KEY_A 0
+        0.210 KEY_SEMICOLON 0",
+    );
+    assert_eq!(keys, ctrl(KEY_C));
+}
+
+#[test]
+fn repeats_of_the_keys_are_dropped() {
+    let keys = replay(
+        "0.000 KEY_A 1
+        0.020 KEY_SEMICOLON 1
+        0.300 KEY_A 2
+        0.300 KEY_SEMICOLON 2
+        0.400 KEY_C 1
+        0.450 KEY_C 0
+        0.500 KEY_SEMICOLON 0
+        0.510 KEY_A 0",
+    );
+    assert_eq!(keys, ctrl(KEY_C));
+}
+
+#[test]
+fn keys_pressed_apart_are_not_a_combo() {
+    let keys = replay(
+        "0.000 KEY_A 1
+        0.050 KEY_A 0
+        0.100 KEY_SEMICOLON 1
+        0.150 KEY_SEMICOLON 0",
+    );
+    assert_eq!(keys, [tap(KEY_A), tap(KEY_SEMICOLON)].concat());
+}
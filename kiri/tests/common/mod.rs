@@ -0,0 +1,32 @@
+//! Helpers for the tests replaying traces. Each test uses some of them.
+#![allow(dead_code)]
+
+use kiri::evdev_keys::*;
+use kiri::{parse_trace, Key, KeyConfigRun, KeyInput};
+
+/// The keys sent by the layers of `config` for `trace`.
+pub fn replay(config: impl KeyConfigRun, trace: &str) -> Vec<KeyInput> {
+    config.simulator().replay(&parse_trace(trace).unwrap())
+}
+
+pub fn tap(key: Key) -> Vec<KeyInput> {
+    vec![KeyInput::press(key), KeyInput::release(key)]
+}
+
+pub fn shifted(key: Key) -> Vec<KeyInput> {
+    [
+        vec![KeyInput::press(KEY_LEFTSHIFT)],
+        tap(key),
+        vec![KeyInput::release(KEY_LEFTSHIFT)],
+    ]
+    .concat()
+}
+
+pub fn ctrl(key: Key) -> Vec<KeyInput> {
+    [
+        vec![KeyInput::press(KEY_LEFTCTRL)],
+        tap(key),
+        vec![KeyInput::release(KEY_LEFTCTRL)],
+    ]
+    .concat()
+}
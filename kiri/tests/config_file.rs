@@ -0,0 +1,164 @@
+mod common;
+
+use common::{ctrl, tap};
+use kiri::evdev_keys::*;
+use kiri::{parse_layers, AddLayer, KeyConfig, KeyInput};
+
+fn replay(source: &str, trace: &str) -> Vec<KeyInput> {
+    common::replay(
+        KeyConfig::default().add_layer(parse_layers(source).unwrap()),
+        trace,
+    )
+}
+
+const MODIFIER_LAYER: &str = r#"
+[[layer]]
+type = "modifier"
+name = "arrows"
+trigger = "CAPSLOCK"
+
+[layer.mappings]
+J = "LEFT"
+S = "LEFTCTRL+S"
+
+[[layer.sub_mode]]
+name = "Word"
+key = "E"
+mappings = { J = "LEFTCTRL+LEFT" }
+"#;
+
+#[test]
+fn a_modifier_layer_maps_keys_while_its_trigger_is_held() {
+    let keys = replay(
+        MODIFIER_LAYER,
+        "0.000 KEY_CAPSLOCK 1
+        0.100 KEY_J 1
+        0.150 KEY_J 0
+        0.200 KEY_S 1
+        0.250 KEY_S 0
+        0.300 KEY_CAPSLOCK 0
+        0.400 KEY_J 1
+        0.450 KEY_J 0",
+    );
+    assert_eq!(keys, [tap(KEY_LEFT), ctrl(KEY_S), tap(KEY_J)].concat());
+}
+
+#[test]
+fn a_sub_mode_lasts_while_its_key_is_held() {
+    let keys = replay(
+        MODIFIER_LAYER,
+        "0.000 KEY_CAPSLOCK 1
+        0.100 KEY_E 1
+        0.200 KEY_J 1
+        0.250 KEY_J 0
+        0.300 KEY_E 0
+        0.400 KEY_J 1
+        0.450 KEY_J 0
+        0.500 KEY_CAPSLOCK 0",
+    );
+    assert_eq!(keys, [ctrl(KEY_LEFT), tap(KEY_LEFT)].concat());
+}
+
+#[test]
+fn errors_name_the_layer() {
+    let error = |source: &str| parse_layers(source).err().unwrap();
+    assert_eq!(
+        error(
+            r#"
+            [[layer]]
+            type = "modifier"
+            name = "arrows"
+            trigger = "CAPSLOCK"
+            mappings = { J = "NO_SUCH_KEY" }
+            "#
+        ),
+        r#"arrows: unknown key "NO_SUCH_KEY""#
+    );
+    assert_eq!(
+        error(
+            r#"
+            [[layer]]
+            type = "modifier"
+            name = "arrows"
+            trigger = "CAPSLOCK"
+            mappings = { E = "UP" }
+            sub_mode = [{ name = "Word", key = "E" }]
+            "#
+        ),
+        "arrows: KEY_E ↓ is used twice in Active"
+    );
+}
+
+const STATES_LAYER: &str = r#"
+[[layer]]
+type = "states"
+name = "nav"
+states = ["Normal", "Nav"]
+
+[[layer.entry]]
+states = ["Normal"]
+input = "press MUHENKAN"
+transition = "Nav"
+
+[[layer.entry]]
+states = ["Nav"]
+input = "press MUHENKAN"
+transition = "Normal"
+
+[[layer.entry]]
+states = ["Nav"]
+input = "H"
+output = ["LEFT"]
+
+[[layer.pair]]
+states = ["Normal", "Nav"]
+input = ["J", "K"]
+output = ["ESC"]
+threshold = 50
+"#;
+
+#[test]
+fn a_states_layer_switches_states_with_its_entries() {
+    let keys = replay(
+        STATES_LAYER,
+        "0.000 KEY_H 1
+        0.050 KEY_H 0
+        0.100 KEY_MUHENKAN 1
+        0.150 KEY_MUHENKAN 0
+        0.200 KEY_H 1
+        0.250 KEY_H 0
+        0.300 KEY_MUHENKAN 1
+        0.350 KEY_MUHENKAN 0
+        0.400 KEY_H 1
+        0.450 KEY_H 0",
+    );
+    assert_eq!(keys, [tap(KEY_H), tap(KEY_LEFT), tap(KEY_H)].concat());
+}
+
+#[test]
+fn pairs_of_a_states_layer_are_order_insensitive() {
+    let keys = replay(
+        STATES_LAYER,
+        "0.000 KEY_K 1
+        0.010 KEY_J 1
+        0.100 KEY_K 0
+        0.110 KEY_J 0",
+    );
+    assert_eq!(keys, tap(KEY_ESC));
+}
+
+#[test]
+fn states_must_be_listed() {
+    let error = parse_layers(
+        r#"
+        [[layer]]
+        type = "states"
+        name = "nav"
+        states = ["Normal"]
+        entry = [{ states = ["Normal"], input = "H", transition = "Nav" }]
+        "#,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(error, r#"nav: state "Nav" is not in `states`"#);
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::tap;
+use kiri::evdev_keys::*;
+use kiri::{AddLayer, DebounceAlgorithm, DebounceConfig, KeyConfig, KeyInput, RemapLayer};
+
+fn replay(config: DebounceConfig, trace: &str) -> Vec<KeyInput> {
+    common::replay(
+        KeyConfig::default()
+            .debounce(config)
+            .add_layer(RemapLayer::<()>::default()),
+        trace,
+    )
+}
+
+fn config(algorithm: DebounceAlgorithm) -> DebounceConfig {
+    DebounceConfig {
+        algorithm,
+        window: 20,
+        per_key: Vec::new(),
+    }
+}
+
+#[test]
+fn eager_drops_changes_within_the_window() {
+    let keys = replay(
+        config(DebounceAlgorithm::Eager),
+        "0.000 KEY_A 1
+        0.005 KEY_A 0
+        0.010 KEY_A 1
+        0.100 KEY_A 0",
+    );
+    assert_eq!(keys, tap(KEY_A));
+}
+
+#[test]
+fn eager_sends_the_state_left_at_the_end_of_the_window() {
+    let keys = replay(
+        config(DebounceAlgorithm::Eager),
+        "0.000 KEY_A 1
+        0.010 KEY_A 0",
+    );
+    assert_eq!(keys, tap(KEY_A));
+}
+
+#[test]
+fn deferred_drops_noise_shorter_than_the_window() {
+    let keys = replay(
+        config(DebounceAlgorithm::Deferred),
+        "0.000 KEY_A 1
+        0.005 KEY_A 0
+        0.100 KEY_B 1
+        0.200 KEY_B 0",
+    );
+    assert_eq!(keys, tap(KEY_B));
+}
+
+#[test]
+fn deferred_sends_a_change_once_the_key_settles() {
+    let keys = replay(
+        config(DebounceAlgorithm::Deferred),
+        "0.000 KEY_A 1
+        0.005 KEY_A 0
+        0.010 KEY_A 1
+        0.100 KEY_A 0",
+    );
+    assert_eq!(keys, tap(KEY_A));
+}
+
+#[test]
+fn per_key_windows_override_the_window() {
+    let config = DebounceConfig {
+        per_key: vec![(KEY_A, 20)],
+        window: 0,
+        ..config(DebounceAlgorithm::Eager)
+    };
+    let keys = replay(
+        config,
+        "0.000 KEY_A 1
+        0.005 KEY_A 0
+        0.010 KEY_A 1
+        0.100 KEY_A 0
+        0.200 KEY_B 1
+        0.205 KEY_B 0
+        0.210 KEY_B 1
+        0.300 KEY_B 0",
+    );
+    assert_eq!(keys, [tap(KEY_A), tap(KEY_B), tap(KEY_B)].concat());
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::{shifted, tap};
+use kiri::evdev_keys::*;
+use kiri::{
+    parse_trace, AddLayer, ExpansionLayer, Key, KeyConfig, KeyConfigRun, KeyInput, KeyboardLayout,
+};
+
+fn expansion(layout: KeyboardLayout) -> ExpansionLayer {
+    ExpansionLayer {
+        layer_name: "expansion",
+        layout,
+        abbreviations: vec![(";p".to_string(), "(p)".to_string())],
+        suspend_keys: Some((KEY_NUMERIC_0, KEY_NUMERIC_1)),
+    }
+}
+
+/// The keys typing `;p` and replacing it with `(p)`.
+fn expanded(parens: (Key, Key)) -> Vec<KeyInput> {
+    [
+        tap(KEY_SEMICOLON),
+        tap(KEY_BACKSPACE),
+        shifted(parens.0),
+        tap(KEY_P),
+        shifted(parens.1),
+    ]
+    .concat()
+}
+
+const TYPE_P: &str = "0.000 KEY_SEMICOLON 1
+    0.050 KEY_SEMICOLON 0
+    0.100 KEY_P 1
+    0.150 KEY_P 0";
+
+#[test]
+fn expansions_are_typed_in_the_layout() {
+    let trace = parse_trace(TYPE_P).unwrap();
+    for (layout, parens) in [
+        (KeyboardLayout::Us, (KEY_9, KEY_0)),
+        (KeyboardLayout::Jis, (KEY_8, KEY_9)),
+    ] {
+        let mut simulator = KeyConfig::default()
+            .add_layer(expansion(layout))
+            .simulator();
+        assert_eq!(simulator.replay(&trace), expanded(parens), "{layout:?}");
+    }
+}
+
+#[test]
+fn reset_resumes_a_suspended_expansion() {
+    let mut simulator = KeyConfig::default()
+        .add_layer(expansion(KeyboardLayout::Us))
+        .simulator();
+    simulator.replay(&parse_trace("0.000 KEY_NUMERIC_0 1\n0.050 KEY_NUMERIC_0 0").unwrap());
+    let trace = parse_trace(TYPE_P).unwrap();
+    assert_eq!(
+        simulator.replay(&trace),
+        [tap(KEY_SEMICOLON), tap(KEY_P)].concat()
+    );
+    simulator.reset();
+    assert_eq!(simulator.replay(&trace), expanded((KEY_9, KEY_0)));
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::{shifted, tap};
+use kiri::evdev_keys::*;
+use kiri::{
+    AddLayer, Key, KeyConfig, KeyInput, KeyPattern, PairPatternEntry, PairRemapEntry, PatternInput,
+    PatternOutput, RemapLayer, SinglePatternEntry, SingleRemapEntry,
+};
+
+fn replay(layer: RemapLayer<()>, trace: &str) -> Vec<KeyInput> {
+    common::replay(KeyConfig::default().add_layer(layer), trace)
+}
+
+/// A trace of taps of `keys`, 100 ms apart.
+fn taps(keys: &[&str]) -> String {
+    let mut trace = String::new();
+    for (i, key) in keys.iter().enumerate() {
+        let ms = i * 100;
+        trace += &format!("0.{ms:03} {key} 1\n0.{:03} {key} 0\n", ms + 50);
+    }
+    trace
+}
+
+/// Entries sending `output` instead of a tap of the keys matched by `pattern`.
+fn pattern_entries(pattern: KeyPattern, output: Key) -> Vec<SinglePatternEntry<()>> {
+    vec![
+        SinglePatternEntry {
+            condition: (),
+            input: PatternInput::press(pattern.clone()),
+            output: vec![PatternOutput::press(output)],
+            transition: (),
+        },
+        SinglePatternEntry {
+            condition: (),
+            input: PatternInput::release(pattern),
+            output: vec![PatternOutput::release(output)],
+            transition: (),
+        },
+    ]
+}
+
+/// Entries sending a shifted key for each key matched by `pattern`.
+fn shift_entries(pattern: KeyPattern) -> Vec<SinglePatternEntry<()>> {
+    vec![
+        SinglePatternEntry {
+            condition: (),
+            input: PatternInput::press(pattern.clone()),
+            output: vec![
+                PatternOutput::press(KEY_LEFTSHIFT),
+                PatternOutput::PressMatched,
+            ],
+            transition: (),
+        },
+        SinglePatternEntry {
+            condition: (),
+            input: PatternInput::release(pattern),
+            output: vec![
+                PatternOutput::ReleaseMatched,
+                PatternOutput::release(KEY_LEFTSHIFT),
+            ],
+            transition: (),
+        },
+    ]
+}
+
+#[test]
+fn press_matched_outputs_the_matched_key() {
+    let layer = RemapLayer {
+        single_pattern_entries: shift_entries(KeyPattern::Letters),
+        ..Default::default()
+    };
+    let keys = replay(layer, &taps(&["KEY_A", "KEY_Q", "KEY_1"]));
+    assert_eq!(keys, [shifted(KEY_A), shifted(KEY_Q), tap(KEY_1)].concat());
+}
+
+#[test]
+fn an_entry_for_a_key_beats_a_pattern() {
+    let layer = RemapLayer {
+        single_remap_entries: vec![
+            SingleRemapEntry {
+                condition: (),
+                input: KeyInput::press(KEY_A),
+                output: vec![KeyInput::press(KEY_B)],
+                transition: (),
+            },
+            SingleRemapEntry {
+                condition: (),
+                input: KeyInput::release(KEY_A),
+                output: vec![KeyInput::release(KEY_B)],
+                transition: (),
+            },
+        ],
+        single_pattern_entries: shift_entries(KeyPattern::Any),
+        ..Default::default()
+    };
+    let keys = replay(layer, &taps(&["KEY_A", "KEY_C"]));
+    assert_eq!(keys, [tap(KEY_B), shifted(KEY_C)].concat());
+}
+
+#[test]
+fn earlier_patterns_beat_later_ones() {
+    let layer = RemapLayer {
+        single_pattern_entries: [
+            pattern_entries(KeyPattern::Digits, KEY_X),
+            pattern_entries(KeyPattern::Keys(vec![KEY_1, KEY_Q]), KEY_Y),
+            pattern_entries(KeyPattern::Any, KEY_Z),
+        ]
+        .concat(),
+        ..Default::default()
+    };
+    let keys = replay(layer, &taps(&["KEY_1", "KEY_Q", "KEY_SPACE"]));
+    assert_eq!(keys, [tap(KEY_X), tap(KEY_Y), tap(KEY_Z)].concat());
+}
+
+#[test]
+fn except_leaves_out_the_listed_keys() {
+    let layer = RemapLayer {
+        single_pattern_entries: shift_entries(KeyPattern::Letters.except(&[KEY_J])),
+        ..Default::default()
+    };
+    let keys = replay(layer, &taps(&["KEY_J", "KEY_K"]));
+    assert_eq!(keys, [tap(KEY_J), shifted(KEY_K)].concat());
+}
+
+#[test]
+fn a_pair_entry_beats_a_pair_pattern() {
+    let layer = RemapLayer {
+        pair_remap_entries: vec![PairRemapEntry {
+            condition: (),
+            input: [KeyInput::press(KEY_D), KeyInput::press(KEY_J)],
+            output: tap(KEY_ESC),
+            transition: (),
+            threshold: 50,
+            hold: false,
+        }],
+        pair_pattern_entries: vec![PairPatternEntry {
+            condition: (),
+            input: (
+                PatternInput::press(KeyPattern::Letters),
+                KeyInput::press(KEY_J),
+            ),
+            output: vec![
+                PatternOutput::PressMatched,
+                PatternOutput::ReleaseMatched,
+                PatternOutput::press(KEY_TAB),
+                PatternOutput::release(KEY_TAB),
+            ],
+            transition: (),
+            threshold: 50,
+            hold: false,
+        }],
+        ..Default::default()
+    };
+    let keys = replay(
+        layer,
+        "0.000 KEY_D 1
+        0.010 KEY_J 1
+        0.100 KEY_D 0
+        0.110 KEY_J 0
+        1.000 KEY_F 1
+        1.010 KEY_J 1
+        1.100 KEY_F 0
+        1.110 KEY_J 0",
+    );
+    assert_eq!(keys, [tap(KEY_ESC), tap(KEY_F), tap(KEY_TAB)].concat());
+}
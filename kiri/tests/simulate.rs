@@ -0,0 +1,88 @@
+use kiri::evdev_keys::*;
+use kiri::{parse_trace, AddLayer, KeyConfig, KeyConfigRun, KeyInput, PairRemapEntry, RemapLayer};
+
+fn pair_layer() -> RemapLayer<()> {
+    RemapLayer {
+        pair_remap_entries: PairRemapEntry {
+            condition: (),
+            input: [KeyInput::press(KEY_D), KeyInput::press(KEY_S)],
+            output: vec![KeyInput::press(KEY_ESC), KeyInput::release(KEY_ESC)],
+            transition: (),
+            threshold: 50,
+            hold: false,
+        }
+        .order_insensitive()
+        .collect(),
+        layer_name: "pair",
+        ..Default::default()
+    }
+}
+
+#[test]
+fn replaying_a_pair_gives_the_same_keys_every_time() {
+    let trace = parse_trace(
+        "0.000 KEY_D 1
+        0.010 KEY_S 1
+        0.100 KEY_D 0
+        0.110 KEY_S 0
+        0.500 KEY_A 1
+        0.560 KEY_A 0",
+    )
+    .unwrap();
+    // Layers passing keys on delay them on their way to the pair.
+    let empty = || RemapLayer::<()>::default();
+    let mut simulator = KeyConfig::default()
+        .add_layer(empty())
+        .add_layer(empty())
+        .add_layer(empty())
+        .add_layer(pair_layer())
+        .simulator();
+    let expected = vec![
+        KeyInput::press(KEY_ESC),
+        KeyInput::release(KEY_ESC),
+        KeyInput::press(KEY_A),
+        KeyInput::release(KEY_A),
+    ];
+    for _ in 0..20 {
+        assert_eq!(simulator.replay(&trace), expected);
+    }
+}
+
+#[test]
+fn keys_stamped_out_of_order_are_handled_in_the_order_they_arrive() {
+    let mut simulator = KeyConfig::default().add_layer(pair_layer()).simulator();
+    // D from another device is stamped within the threshold of S,
+    // but arrives after the threshold has passed.
+    let late = parse_trace(
+        "0.000 KEY_S 1
+        0.100 KEY_A 1
+        0.020 KEY_D 1
+        0.150 KEY_A 0
+        0.160 KEY_S 0
+        0.170 KEY_D 0",
+    )
+    .unwrap();
+    assert_eq!(
+        simulator.replay(&late),
+        [
+            KeyInput::press(KEY_S),
+            KeyInput::press(KEY_A),
+            KeyInput::press(KEY_D),
+            KeyInput::release(KEY_A),
+            KeyInput::release(KEY_S),
+            KeyInput::release(KEY_D),
+        ]
+    );
+    // Arriving within the threshold, it is paired, stamped as if pressed with S.
+    let early = parse_trace(
+        "0.030 KEY_S 1
+        0.020 KEY_D 1
+        0.100 KEY_S 0
+        0.110 KEY_D 0",
+    )
+    .unwrap();
+    assert_eq!(
+        simulator.replay(&early),
+        [KeyInput::press(KEY_ESC), KeyInput::release(KEY_ESC)]
+    );
+}
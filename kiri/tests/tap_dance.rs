@@ -0,0 +1,94 @@
+mod common;
+
+use common::tap;
+use kiri::evdev_keys::*;
+use kiri::{AddLayer, KeyConfig, KeyInput, RemapLayer, TapDanceEntry};
+
+/// The menu key sends itself, or Ctrl+Enter on a double tap, like the config of the remapper.
+fn replay(trace: &str) -> Vec<KeyInput> {
+    let layer = RemapLayer {
+        tap_dance_entries: vec![TapDanceEntry {
+            condition: (),
+            input: KEY_COMPOSE,
+            taps: vec![
+                (vec![KeyInput::press(KEY_COMPOSE)], ()),
+                (
+                    vec![KeyInput::press(KEY_LEFTCTRL), KeyInput::press(KEY_ENTER)],
+                    (),
+                ),
+            ],
+            threshold: 200,
+        }],
+        ..Default::default()
+    };
+    common::replay(KeyConfig::default().add_layer(layer), trace)
+}
+
+#[test]
+fn a_tap_settles_after_the_threshold() {
+    let keys = replay(
+        "0.000 KEY_COMPOSE 1
+        0.050 KEY_COMPOSE 0",
+    );
+    assert_eq!(keys, tap(KEY_COMPOSE));
+}
+
+#[test]
+fn a_double_tap_sends_the_second_output() {
+    let keys = replay(
+        "0.000 KEY_COMPOSE 1
+        0.050 KEY_COMPOSE 0
+        0.150 KEY_COMPOSE 1
+        0.200 KEY_COMPOSE 0",
+    );
+    assert_eq!(
+        keys,
+        [
+            KeyInput::press(KEY_LEFTCTRL),
+            KeyInput::press(KEY_ENTER),
+            KeyInput::release(KEY_ENTER),
+            KeyInput::release(KEY_LEFTCTRL),
+        ]
+    );
+}
+
+#[test]
+fn taps_further_apart_than_the_threshold_count_separately() {
+    let keys = replay(
+        "0.000 KEY_COMPOSE 1
+        0.050 KEY_COMPOSE 0
+        0.400 KEY_COMPOSE 1
+        0.450 KEY_COMPOSE 0",
+    );
+    assert_eq!(keys, [tap(KEY_COMPOSE), tap(KEY_COMPOSE)].concat());
+}
+
+#[test]
+fn another_key_settles_the_count() {
+    let keys = replay(
+        "0.000 KEY_COMPOSE 1
+        0.050 KEY_COMPOSE 0
+        0.100 KEY_A 1
+        0.150 KEY_A 0",
+    );
+    assert_eq!(keys, [tap(KEY_COMPOSE), tap(KEY_A)].concat());
+}
+
+#[test]
+fn the_output_is_held_while_the_key_is() {
+    let keys = replay(
+        "0.000 KEY_COMPOSE 1
+        0.500 KEY_A 1
+        0.550 KEY_A 0
+        0.600 KEY_COMPOSE 0",
+    );
+    assert_eq!(
+        keys,
+        [
+            KeyInput::press(KEY_COMPOSE),
+            KeyInput::press(KEY_A),
+            KeyInput::release(KEY_A),
+            KeyInput::release(KEY_COMPOSE),
+        ]
+    );
+}
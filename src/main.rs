@@ -1,9 +1,15 @@
 use env_logger::Env;
 use kiri::evdev_keys::*;
 use kiri::{
-    AddLayer, Key, KeyConfig, KeyConfigRun, KeyInput, PairRemapEntry, RemapLayer, SingleRemapEntry,
+    chattering_report, diagnose_chattering, load_trace, record_trace, send_command, AddLayer,
+    CommandLayer, DebounceAlgorithm, DebounceConfig, ExpansionLayer, Key, KeyConfig, KeyConfigRun,
+    KeyInput, KeyPattern, KeyboardLayout, LayersFile, LeaderLayer, MacroConfig, MacroTiming,
+    OneShotLayer, PairPatternEntry, PairRemapEntry, PatternInput, PatternOutput, RemapLayer,
+    SinglePatternEntry, SingleRemapEntry, StateHook, TapDanceEntry,
 };
-use std::iter;
+use std::time::Duration;
+
+const CONTROL_SOCKET: &str = "/run/remapper.sock";
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 enum StateGeta {
@@ -307,6 +313,7 @@ fn mk_config() -> RemapLayer<StateGeta> {
             [KEY_D, KEY_S],
             vec![
                 KeyInput::press(KEY_NUMERIC_0),
+                KeyInput::release(KEY_NUMERIC_0),
                 KeyInput::press(KEY_LEFTMETA),
                 KeyInput::press(KEY_SPACE),
                 KeyInput::release(KEY_SPACE),
@@ -325,6 +332,7 @@ fn mk_config() -> RemapLayer<StateGeta> {
                 KeyInput::release(KEY_SPACE),
                 KeyInput::release(KEY_LEFTMETA),
                 KeyInput::press(KEY_NUMERIC_1),
+                KeyInput::release(KEY_NUMERIC_1),
             ],
             Some(Normal),
         ),
@@ -369,6 +377,7 @@ fn mk_config() -> RemapLayer<StateGeta> {
                             .collect(),
                         transition: t.unwrap_or(*c),
                         threshold: THRESHOLD,
+                        hold: false,
                     }
                     .order_insensitive()
                 })
@@ -384,6 +393,7 @@ fn mk_config() -> RemapLayer<StateGeta> {
                                 output: o.clone(),
                                 transition: t.unwrap_or(*c),
                                 threshold: THRESHOLD,
+                                hold: false,
                             }
                             .order_insensitive()
                         })
@@ -413,204 +423,38 @@ fn mk_config() -> RemapLayer<StateGeta> {
             })
             .chain(modifiers_trans)
             .collect(),
+        tap_dance_entries: Vec::new(),
+        single_pattern_entries: Vec::new(),
+        pair_pattern_entries: Vec::new(),
         layer_name: "big config",
         initial_state: Normal,
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
-enum StateGrave {
-    Normal,
-    Grave,
-    Grave1,
-    Grave2,
-}
-
-fn config_grave_arrow() -> RemapLayer<StateGrave> {
-    use StateGrave::*;
-    let grave_side: [(_, _, &[Key], _); 8] = [
-        (Grave, KEY_J, &[KEY_LEFTMETA], KEY_PAGEUP),
-        (Grave, KEY_L, &[KEY_LEFTMETA], KEY_PAGEDOWN),
-        (Grave1, KEY_J, &[KEY_LEFTMETA, KEY_LEFTSHIFT], KEY_PAGEUP),
-        (Grave1, KEY_L, &[KEY_LEFTMETA, KEY_LEFTSHIFT], KEY_PAGEDOWN),
-        (Grave2, KEY_J, &[KEY_LEFTMETA, KEY_LEFTSHIFT], KEY_LEFT),
-        (Grave2, KEY_L, &[KEY_LEFTMETA, KEY_LEFTSHIFT], KEY_RIGHT),
-        (Grave2, KEY_I, &[KEY_LEFTMETA, KEY_LEFTSHIFT], KEY_UP),
-        (Grave2, KEY_K, &[KEY_LEFTMETA, KEY_LEFTSHIFT], KEY_DOWN),
-    ];
-    let grave_side = grave_side.iter().map(|&(c, i, o1, o2)| SingleRemapEntry {
-        condition: c,
-        input: KeyInput::press(i),
-        output: o1
-            .iter()
-            .map(|o1| KeyInput::press(*o1))
-            .chain(vec![KeyInput::press(o2), KeyInput::release(o2)])
-            .chain(o1.iter().map(|o1| KeyInput::release(*o1)))
-            .collect(),
-        transition: c,
-    });
-    let single_remap_entries: &[(&[StateGrave], KeyInput, &[KeyInput], StateGrave)] = &[
-        (&[Normal, Grave], KeyInput::press(KEY_GRAVE), &[], Grave),
-        (
-            &[Grave, Grave1, Grave2],
-            KeyInput::press(KEY_1),
-            &[],
-            Grave1,
-        ),
-        (
-            &[Grave, Grave1, Grave2],
-            KeyInput::press(KEY_2),
-            &[],
-            Grave2,
-        ),
-        (
-            &[Normal, Grave, Grave1, Grave2],
-            KeyInput::release(KEY_GRAVE),
-            &[],
-            Normal,
-        ),
-        (&[Grave1], KeyInput::release(KEY_1), &[], Grave),
-        (&[Grave1], KeyInput::release(KEY_2), &[], Grave),
-    ];
-    let single_hotkeys = single_remap_entries.iter().flat_map(|(c, i, o, t)| {
-        c.iter().map(move |c| SingleRemapEntry {
-            condition: *c,
-            input: *i,
-            output: o.to_vec(),
-            transition: *t,
-        })
-    });
-    RemapLayer {
-        pair_remap_entries: Vec::new(),
-        single_remap_entries: single_hotkeys.into_iter().chain(grave_side).collect(),
-        layer_name: "grave arrows",
-        initial_state: Normal,
+/// Layers loaded from `--layers <path>`, or from `layers.toml` built into the binary.
+/// They are read once the remapper has switched to the user of `--user`.
+fn config_file_layers(args: &[String]) -> LayersFile {
+    match args.iter().position(|a| a == "--layers") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) => LayersFile::load(path),
+            None => {
+                eprintln!("--layers needs a path");
+                std::process::exit(1);
+            }
+        },
+        None => LayersFile::parse(include_str!("../layers.toml")),
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
-enum StateCapsLock {
-    Normal,
-    CL,
-    Clw,
-    Cle,
-    Clr,
-    Clf,
-    ClTab,
-}
-
-fn config_caps_lock_arrow() -> RemapLayer<StateCapsLock> {
-    use StateCapsLock::*;
-    let capslock_side: [(_, _, &[_], _); 33] = [
-        (CL, KEY_I, &[], KEY_UP),
-        (CL, KEY_J, &[], KEY_LEFT),
-        (CL, KEY_K, &[], KEY_DOWN),
-        (CL, KEY_L, &[], KEY_RIGHT),
-        (CL, KEY_GRAVE, &[], KEY_F15),
-        (CL, KEY_ENTER, &[KEY_LEFTCTRL], KEY_S),
-        (CL, KEY_N, &[KEY_LEFTCTRL], KEY_C),
-        (CL, KEY_M, &[KEY_LEFTCTRL], KEY_V),
-        (CL, KEY_U, &[KEY_LEFTCTRL], KEY_Z),
-        (CL, KEY_O, &[KEY_LEFTCTRL], KEY_Y),
-        (CL, KEY_DOT, &[KEY_LEFTCTRL], KEY_DOT),
-        (CL, KEY_P, &[KEY_LEFTCTRL], KEY_P),
-        (CL, KEY_COMMA, &[KEY_LEFTCTRL], KEY_F8),
-        (CL, KEY_H, &[], KEY_ESC),
-        (CL, KEY_LEFTBRACE, &[KEY_LEFTCTRL, KEY_LEFTALT], KEY_MINUS),
-        (CL, KEY_RIGHTBRACE, &[KEY_LEFTSHIFT, KEY_LEFTCTRL], KEY_RO),
-        (CL, KEY_BACKSLASH, &[KEY_LEFTSHIFT, KEY_LEFTCTRL], KEY_N),
-        (Cle, KEY_I, &[KEY_LEFTCTRL], KEY_UP),
-        (Cle, KEY_J, &[KEY_LEFTCTRL], KEY_LEFT),
-        (Cle, KEY_K, &[KEY_LEFTCTRL], KEY_DOWN),
-        (Cle, KEY_L, &[KEY_LEFTCTRL], KEY_RIGHT),
-        (Clr, KEY_I, &[KEY_LEFTMETA], KEY_I),
-        (Clr, KEY_J, &[KEY_LEFTMETA], KEY_J),
-        (Clr, KEY_K, &[KEY_LEFTMETA], KEY_K),
-        (Clr, KEY_L, &[KEY_LEFTMETA], KEY_L),
-        (Clf, KEY_J, &[], KEY_HOME),
-        (Clf, KEY_L, &[], KEY_END),
-        (Clf, KEY_I, &[KEY_LEFTCTRL], KEY_F10),
-        (Clf, KEY_K, &[KEY_LEFTCTRL], KEY_F9),
-        (Clw, KEY_J, &[KEY_LEFTCTRL], KEY_PAGEUP),
-        (Clw, KEY_L, &[KEY_LEFTCTRL], KEY_PAGEDOWN),
-        (ClTab, KEY_J, &[KEY_LEFTCTRL], KEY_PAGEUP),
-        (ClTab, KEY_L, &[KEY_LEFTCTRL], KEY_PAGEDOWN),
-    ];
-    let capslock_side = capslock_side
-        .iter()
-        .map(|&(c, i, o1, o2)| SingleRemapEntry {
-            condition: c,
-            input: KeyInput::press(i),
-            output: o1
-                .iter()
-                .map(|o1| KeyInput::press(*o1))
-                .chain(vec![KeyInput::press(o2), KeyInput::release(o2)])
-                .chain(o1.iter().map(|o1| KeyInput::release(*o1)))
-                .collect(),
-            transition: c,
-        });
-    let single_remap_entries: &[(&[StateCapsLock], KeyInput, &[KeyInput], StateCapsLock)] = &[
-        (&[Normal, CL], KeyInput::press(KEY_CAPSLOCK), &[], CL),
-        (
-            &[CL, Cle, Clr, Clf, Clw, ClTab],
-            KeyInput::press(KEY_E),
-            &[],
-            Cle,
-        ),
-        (
-            &[CL, Cle, Clr, Clf, Clw, ClTab],
-            KeyInput::press(KEY_R),
-            &[],
-            Clr,
-        ),
-        (
-            &[CL, Cle, Clr, Clf, Clw, ClTab],
-            KeyInput::press(KEY_F),
-            &[],
-            Clf,
-        ),
-        (
-            &[CL, Cle, Clr, Clf, Clw, ClTab],
-            KeyInput::press(KEY_W),
-            &[],
-            Clw,
-        ),
-        (
-            &[CL, Cle, Clr, Clf, Clw, ClTab],
-            KeyInput::press(KEY_TAB),
-            &[],
-            ClTab,
-        ),
-        (&[Cle], KeyInput::press(KEY_CAPSLOCK), &[], Cle),
-        (&[Clr], KeyInput::press(KEY_CAPSLOCK), &[], Clr),
-        (&[Clf], KeyInput::press(KEY_CAPSLOCK), &[], Clf),
-        (&[Clw], KeyInput::press(KEY_CAPSLOCK), &[], Clw),
-        (&[ClTab], KeyInput::press(KEY_CAPSLOCK), &[], ClTab),
-        (
-            &[Normal, CL, Cle, Clr, Clf, Clw, ClTab],
-            KeyInput::release(KEY_CAPSLOCK),
-            &[],
-            Normal,
-        ),
-        (&[Cle], KeyInput::release(KEY_E), &[], CL),
-        (&[Clr], KeyInput::release(KEY_R), &[], CL),
-        (&[Clf], KeyInput::release(KEY_F), &[], CL),
-        (&[Clw], KeyInput::release(KEY_W), &[], CL),
-        (&[ClTab], KeyInput::release(KEY_TAB), &[], CL),
-    ];
-    let single_hotkeys = single_remap_entries.iter().flat_map(|(c, i, o, t)| {
-        c.iter().map(move |c| SingleRemapEntry {
-            condition: *c,
-            input: *i,
-            output: o.to_vec(),
-            transition: *t,
-        })
-    });
-    RemapLayer {
-        pair_remap_entries: Vec::new(),
-        single_remap_entries: single_hotkeys.chain(capslock_side).collect(),
-        layer_name: "caps lock arrows",
-        initial_state: Normal,
+/// The user from `--user <name>`, which the remapper switches to once the devices are open.
+fn config_user(args: &[String]) -> Option<String> {
+    let i = args.iter().position(|a| a == "--user")?;
+    match args.get(i + 1) {
+        Some(user) => Some(user.clone()),
+        None => {
+            eprintln!("--user needs a name");
+            std::process::exit(1);
+        }
     }
 }
 
@@ -626,16 +470,17 @@ fn config_sands() -> RemapLayer<StateSands> {
     use StateSands::*;
     #[allow(clippy::type_complexity)]
     let config: &[(&[StateSands], KeyInput, &[KeyInput], Option<StateSands>)] = &[
+        // The markers of Japanese input go on to the expansion layer.
         (
             &[Normal, Space, Shift, JpInput],
             KeyInput::press(KEY_NUMERIC_0),
-            &[],
+            &[KeyInput::press(KEY_NUMERIC_0)],
             Some(JpInput),
         ),
         (
             &[Normal, Space, Shift, JpInput],
             KeyInput::press(KEY_NUMERIC_1),
-            &[],
+            &[KeyInput::press(KEY_NUMERIC_1)],
             Some(Normal),
         ),
         (
@@ -670,17 +515,18 @@ fn config_sands() -> RemapLayer<StateSands> {
             transition: t.unwrap_or(*c),
         })
     });
-    let config2 = all_keys()
-        .filter(|k| *k != KEY_SPACE)
-        .map(|k| SingleRemapEntry {
-            condition: Space,
-            input: KeyInput::press(k),
-            output: vec![KeyInput::press(k)],
-            transition: Shift,
-        });
     RemapLayer {
         pair_remap_entries: Vec::new(),
-        single_remap_entries: config.chain(config2).collect(),
+        single_remap_entries: config.collect(),
+        tap_dance_entries: Vec::new(),
+        // Keys with entries above keep them.
+        single_pattern_entries: vec![SinglePatternEntry {
+            condition: Space,
+            input: PatternInput::press(KeyPattern::Any),
+            output: vec![PatternOutput::PressMatched],
+            transition: Shift,
+        }],
+        pair_pattern_entries: Vec::new(),
         layer_name: "SandS",
         initial_state: Normal,
     }
@@ -705,6 +551,22 @@ fn config_simple_remap() -> RemapLayer<()> {
                 transition: (),
             }))
             .collect(),
+        // The menu key, or Ctrl+Enter on a double tap, held while the menu key is held.
+        // Not on HENKAN, whose Enter would wait for the threshold and stop repeating.
+        tap_dance_entries: vec![TapDanceEntry {
+            condition: (),
+            input: KEY_COMPOSE,
+            taps: vec![
+                (vec![KeyInput::press(KEY_COMPOSE)], ()),
+                (
+                    vec![KeyInput::press(KEY_LEFTCTRL), KeyInput::press(KEY_ENTER)],
+                    (),
+                ),
+            ],
+            threshold: 200,
+        }],
+        single_pattern_entries: Vec::new(),
+        pair_pattern_entries: Vec::new(),
         layer_name: "simple remap",
         initial_state: (),
     }
@@ -719,68 +581,129 @@ enum StateShiftRelease {
 
 fn config_shift_release() -> RemapLayer<StateShiftRelease> {
     use StateShiftRelease::*;
-    let ps = all_keys()
-        .filter(|k| *k != KEY_LEFTSHIFT && *k != KEY_SPACE)
-        .map(|k| PairRemapEntry {
-            condition: ContinuousShift,
-            input: [KeyInput::press(k), KeyInput::release(KEY_LEFTSHIFT)],
-            output: vec![KeyInput::release(KEY_LEFTSHIFT), KeyInput::press(k)],
-            transition: Normal,
-            threshold: 80,
-        })
-        .collect();
-    let ss = all_keys()
-        .filter(|k| *k != KEY_LEFTSHIFT)
-        .map(|k| SingleRemapEntry {
-            condition: Shift,
-            input: KeyInput::press(k),
-            output: vec![KeyInput::press(k)],
-            transition: ContinuousShift,
-        });
     RemapLayer {
-        pair_remap_entries: ps,
-        single_remap_entries: ss
-            .chain(iter::once(SingleRemapEntry {
+        pair_remap_entries: Vec::new(),
+        single_remap_entries: vec![
+            SingleRemapEntry {
                 condition: Normal,
                 input: KeyInput::press(KEY_LEFTSHIFT),
                 output: vec![KeyInput::press(KEY_LEFTSHIFT)],
                 transition: Shift,
-            }))
-            .chain(iter::once(SingleRemapEntry {
+            },
+            SingleRemapEntry {
                 condition: Shift,
                 input: KeyInput::release(KEY_LEFTSHIFT),
                 output: vec![KeyInput::release(KEY_LEFTSHIFT)],
                 transition: Normal,
-            }))
-            .chain(iter::once(SingleRemapEntry {
+            },
+            SingleRemapEntry {
                 condition: ContinuousShift,
                 input: KeyInput::release(KEY_LEFTSHIFT),
                 output: vec![KeyInput::release(KEY_LEFTSHIFT)],
                 transition: Normal,
-            }))
-            .collect(),
+            },
+        ],
+        tap_dance_entries: Vec::new(),
+        single_pattern_entries: vec![SinglePatternEntry {
+            condition: Shift,
+            input: PatternInput::press(KeyPattern::Any.except(&[KEY_LEFTSHIFT])),
+            output: vec![PatternOutput::PressMatched],
+            transition: ContinuousShift,
+        }],
+        pair_pattern_entries: vec![PairPatternEntry {
+            condition: ContinuousShift,
+            input: (
+                PatternInput::press(KeyPattern::Any.except(&[KEY_LEFTSHIFT, KEY_SPACE])),
+                KeyInput::release(KEY_LEFTSHIFT),
+            ),
+            output: vec![
+                PatternOutput::release(KEY_LEFTSHIFT),
+                PatternOutput::PressMatched,
+            ],
+            transition: Normal,
+            threshold: 80,
+            hold: false,
+        }],
         layer_name: "shift release",
         initial_state: Normal,
     }
 }
 
-// fn config_suppress_chattering() -> RemapLayer<()> {
-//     let pair_remap_entries = all_keys()
-//         .map(|k| PairRemapEntry {
-//             condition: (),
-//             input: [KeyInput::release(k), KeyInput::press(k)],
-//             output: Vec::new(),
-//             transition: (),
-//             threshold: 20,
-//         })
-//         .collect();
-//     RemapLayer {
-//         pair_remap_entries,
-//         single_remap_entries: Vec::new(),
-//         layer_name: "suppress chattering",
-//         initial_state: (),
-//     }
-// }
+fn config_debounce() -> DebounceConfig {
+    DebounceConfig {
+        algorithm: DebounceAlgorithm::Eager,
+        window: 20,
+        per_key: Vec::new(),
+    }
+}
+
+fn config_leader() -> LeaderLayer {
+    let ctrl = |k| {
+        vec![
+            KeyInput::press(KEY_LEFTCTRL),
+            KeyInput::press(k),
+            KeyInput::release(k),
+            KeyInput::release(KEY_LEFTCTRL),
+        ]
+    };
+    LeaderLayer {
+        layer_name: "leader",
+        leader: KEY_KATAKANAHIRAGANA,
+        sequences: vec![
+            (vec![KEY_W, KEY_S], ctrl(KEY_S)),
+            (vec![KEY_W, KEY_Q], ctrl(KEY_W)),
+            (vec![KEY_T, KEY_N], ctrl(KEY_T)),
+            (vec![KEY_T, KEY_R], ctrl(KEY_R)),
+        ],
+        timeout: 1000,
+    }
+}
+
+fn config_commands() -> CommandLayer {
+    CommandLayer {
+        layer_name: "commands",
+        // Caps lock + T in layers.toml.
+        commands: vec![(KEY_PROG1, "x-terminal-emulator".into(), Vec::new())],
+        user: None,
+        env: vec![
+            ("WAYLAND_DISPLAY".to_string(), "wayland-0".to_string()),
+            ("DISPLAY".to_string(), ":0".to_string()),
+        ],
+    }
+}
+
+fn config_one_shot() -> OneShotLayer {
+    OneShotLayer {
+        layer_name: "one-shot",
+        // Tapping caps lock applies the CL mappings of layers.toml to the next key.
+        keys: vec![KEY_CAPSLOCK, KEY_RIGHTSHIFT],
+        timeout: Some(2000),
+        cancel_keys: vec![KEY_ESC],
+    }
+}
+
+fn config_macros() -> MacroConfig {
+    MacroConfig {
+        record_chord: vec![KEY_LEFTCTRL, KEY_RIGHTCTRL, KEY_R],
+        stop_chord: vec![KEY_RIGHTCTRL, KEY_RIGHTSHIFT],
+        play_chord: vec![KEY_RIGHTCTRL, KEY_RIGHTALT],
+        timing: MacroTiming::Compressed(Duration::from_millis(100)),
+        file: "/var/lib/remapper/macros".into(),
+    }
+}
+
+fn config_expansion() -> ExpansionLayer {
+    ExpansionLayer {
+        layer_name: "expansion",
+        layout: KeyboardLayout::Jis,
+        abbreviations: vec![
+            (";lgtm".to_string(), "Looks good to me.".to_string()),
+            (";ty".to_string(), "Thank you!".to_string()),
+        ],
+        // Sent by mk_config when Japanese input is turned on and off.
+        suspend_keys: Some((KEY_NUMERIC_0, KEY_NUMERIC_1)),
+    }
+}
 
 fn config_gc() -> RemapLayer<()> {
     let garbages = &[KEY_NUMERIC_0, KEY_NUMERIC_1];
@@ -788,30 +711,120 @@ fn config_gc() -> RemapLayer<()> {
         pair_remap_entries: Vec::new(),
         single_remap_entries: garbages
             .iter()
-            .map(|k| SingleRemapEntry {
+            .flat_map(|k| [KeyInput::press(*k), KeyInput::release(*k)])
+            .map(|input| SingleRemapEntry {
                 condition: (),
-                input: KeyInput::press(*k),
+                input,
                 output: Vec::new(),
                 transition: (),
             })
             .collect(),
+        tap_dance_entries: Vec::new(),
+        single_pattern_entries: Vec::new(),
+        pair_pattern_entries: Vec::new(),
         layer_name: "gc",
         initial_state: (),
     }
 }
 
-fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
-        .format_timestamp_millis()
-        .init();
-    KeyConfig::default()
-        // .add_layer(config_suppress_chattering())
+/// The config of the remapper. `args` may set `--layers` and `--user`.
+fn config(args: &[String]) -> impl KeyConfigRun {
+    let mut config = KeyConfig::default()
+        .release_all_chord(&[KEY_LEFTCTRL, KEY_RIGHTCTRL, KEY_ESC])
+        .pause_chord(&[KEY_LEFTCTRL, KEY_RIGHTCTRL, KEY_P])
+        .control_socket(CONTROL_SOCKET)
+        .state_hook(StateHook::StateFile("/run/remapper/state".into()))
+        .debounce(config_debounce())
+        .macros(config_macros())
+        .grab_mice()
+        // One stack for all devices, so that caps lock turns the wheel of a mouse
+        // into horizontal scroll and SandS shifts keys on another keyboard.
+        .merge_devices(&[""]);
+    if let Some(user) = config_user(args) {
+        config = config.run_as(user);
+    }
+    config
         .add_layer(config_simple_remap())
-        .add_layer(config_caps_lock_arrow())
-        .add_layer(config_grave_arrow())
+        .add_layer(config_leader())
+        .add_layer(config_one_shot())
+        .add_layer(config_file_layers(args))
+        .add_layer(config_commands())
         .add_layer(mk_config())
         .add_layer(config_sands())
         .add_layer(config_shift_release())
+        .add_layer(config_expansion())
         .add_layer(config_gc())
-        .run();
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("ctl") => {
+            let command = args[1..].join(" ");
+            if let Err(e) = send_command(CONTROL_SOCKET.as_ref(), &command, &mut std::io::stdout())
+            {
+                eprintln!("{CONTROL_SOCKET}: {e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("diagnose-chattering") if args.get(1).is_some_and(|a| a == "--trace") => {
+            let Some(path) = args.get(2) else {
+                eprintln!("--trace needs a path");
+                std::process::exit(1);
+            };
+            let trace = load_trace(path.as_ref()).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+            print!("{}", chattering_report(&trace));
+            return;
+        }
+        Some("diagnose-chattering") => {
+            let minutes = args
+                .get(1)
+                .map_or(Ok(10), |m| m.parse())
+                .unwrap_or_else(|e| {
+                    eprintln!("invalid minutes: {e}");
+                    std::process::exit(1);
+                });
+            diagnose_chattering(Duration::from_secs(minutes * 60));
+            return;
+        }
+        Some("record-trace") => {
+            let Some(path) = args.get(1) else {
+                eprintln!("record-trace needs a path");
+                std::process::exit(1);
+            };
+            let seconds = args
+                .get(2)
+                .map_or(Ok(60), |s| s.parse())
+                .unwrap_or_else(|e| {
+                    eprintln!("invalid seconds: {e}");
+                    std::process::exit(1);
+                });
+            record_trace(path.as_ref(), Duration::from_secs(seconds));
+            return;
+        }
+        Some("replay") => {
+            let Some(path) = args.get(1) else {
+                eprintln!("replay needs a path");
+                std::process::exit(1);
+            };
+            env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+            let trace = load_trace(path.as_ref()).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+            for key in config(&args).simulator().replay(&trace) {
+                println!("{key:?}");
+            }
+            return;
+        }
+        _ => (),
+    }
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
+        .format_timestamp_millis()
+        .init();
+    config(&args).run();
 }